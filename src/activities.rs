@@ -0,0 +1,268 @@
+// Access to Plasma Activities via the org.kde.ActivityManager DBus service.
+//
+// KWin scripts only know activity IDs, so names are resolved here. The
+// functions take a proxy for the ActivityManager, so that they can be pointed
+// at another service with the same interface, e.g. a stand-in for tests.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use dbus::blocking::{Connection, Proxy};
+
+const ACTIVITY_MANAGER: &str = "org.kde.ActivityManager.Activities";
+const SERVICE: &str = "org.kde.ActivityManager";
+const PATH: &str = "/ActivityManager/Activities";
+
+// id, name, description, icon, state
+type ActivityInfo = (String, String, String, String, i32);
+
+pub fn activity_manager(conn: &Connection) -> Proxy<'_, &Connection> {
+    conn.with_proxy(SERVICE, PATH, Duration::from_millis(5000))
+}
+
+// Returns (id, name) of all activities.
+pub fn list_activities(proxy: &Proxy<&Connection>) -> anyhow::Result<Vec<(String, String)>> {
+    let (ids,): (Vec<String>,) = proxy.method_call(ACTIVITY_MANAGER, "ListActivities", ())?;
+    let mut activities = vec![];
+    for id in ids {
+        let (name,): (String,) = proxy.method_call(ACTIVITY_MANAGER, "ActivityName", (&id,))?;
+        activities.push((id, name));
+    }
+    Ok(activities)
+}
+
+// Like list_activities(), but without an ActivityManager there are just no
+// activities to resolve names with.
+fn try_list_activities(dry_run: bool) -> Vec<(String, String)> {
+    if dry_run {
+        return vec![];
+    }
+    Connection::new_session()
+        .map_err(anyhow::Error::from)
+        .and_then(|conn| list_activities(&activity_manager(&conn)))
+        .unwrap_or_else(|err| {
+            log::debug!("Failed to list activities: {err}");
+            vec![]
+        })
+}
+
+// Returns a JS expression for the activity given by name, ID or "current".
+// Anything that isn't a known name is used as an ID, and checked by the script.
+// With dry_run, names aren't resolved, so that no ActivityManager is needed.
+pub fn activity_expr(spec: &str, dry_run: bool) -> anyhow::Result<String> {
+    if spec == "current" {
+        return Ok("workspace.currentActivity".into());
+    }
+    let activities = try_list_activities(dry_run);
+    let id = activities
+        .iter()
+        .find(|(_, name)| name == spec)
+        .map_or(spec, |(id, _)| id);
+    Ok(serde_json::to_string(id)?)
+}
+
+// Returns a JS object literal mapping activity IDs to names, or an empty one
+// with dry_run.
+pub fn activity_names_expr(dry_run: bool) -> anyhow::Result<String> {
+    let names: serde_json::Map<String, serde_json::Value> = try_list_activities(dry_run)
+        .into_iter()
+        .map(|(id, name)| (id, name.into()))
+        .collect();
+    Ok(serde_json::to_string(&names)?)
+}
+
+// Map an activity name, ID or "current" to the activity's ID. This matches
+// activity_expr().
+pub fn activity_id(proxy: &Proxy<&Connection>, spec: &str) -> anyhow::Result<String> {
+    if spec == "current" {
+        return current_activity(proxy);
+    }
+    list_activities(proxy)?
+        .into_iter()
+        .find(|(id, name)| id == spec || name == spec)
+        .map(|(id, _)| id)
+        .ok_or(anyhow!("Invalid activity '{spec}'"))
+}
+
+pub fn current_activity(proxy: &Proxy<&Connection>) -> anyhow::Result<String> {
+    let (id,): (String,) = proxy.method_call(ACTIVITY_MANAGER, "CurrentActivity", ())?;
+    Ok(id)
+}
+
+pub fn activity_name(proxy: &Proxy<&Connection>, id: &str) -> anyhow::Result<String> {
+    let (name,): (String,) = proxy.method_call(ACTIVITY_MANAGER, "ActivityName", (id,))?;
+    Ok(name)
+}
+
+pub fn set_current_activity(proxy: &Proxy<&Connection>, id: &str) -> anyhow::Result<()> {
+    let (ok,): (bool,) = proxy.method_call(ACTIVITY_MANAGER, "SetCurrentActivity", (id,))?;
+    if !ok {
+        return Err(anyhow!("Failed to switch to activity '{id}'"));
+    }
+    Ok(())
+}
+
+// Returns the ID of the new activity.
+pub fn add_activity(proxy: &Proxy<&Connection>, name: &str) -> anyhow::Result<String> {
+    let (id,): (String,) = proxy.method_call(ACTIVITY_MANAGER, "AddActivity", (name,))?;
+    Ok(id)
+}
+
+pub fn stop_activity(proxy: &Proxy<&Connection>, id: &str) -> anyhow::Result<()> {
+    let _: () = proxy.method_call(ACTIVITY_MANAGER, "StopActivity", (id,))?;
+    Ok(())
+}
+
+// Returns (id, name, state) of all activities.
+pub fn list_activities_with_state(
+    proxy: &Proxy<&Connection>,
+) -> anyhow::Result<Vec<(String, String, &'static str)>> {
+    let (activities,): (Vec<ActivityInfo>,) =
+        proxy.method_call(ACTIVITY_MANAGER, "ListActivitiesWithInformation", ())?;
+    Ok(activities
+        .into_iter()
+        .map(|(id, name, _description, _icon, state)| {
+            let state = match state {
+                2 => "running",
+                3 => "starting",
+                4 => "stopped",
+                5 => "stopping",
+                _ => "unknown",
+            };
+            (id, name, state)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use dbus::Message;
+    use serde_json::json;
+
+    use super::*;
+    use crate::native::NativeStep;
+    use crate::test_bus::TestBus;
+
+    const STAND_IN_SERVICE: &str = "org.kdotool.test.ActivityManager";
+    const STAND_IN_PATH: &str = "/Test/Activities";
+
+    // A stand-in for the ActivityManager. Activities are (id, name, state).
+    fn stand_in(bus: &TestBus) -> Arc<Mutex<Vec<(String, String, i32)>>> {
+        let activities = Arc::new(Mutex::new(vec![
+            ("a-1".to_string(), "Work".to_string(), 2),
+            ("a-2".to_string(), "Play".to_string(), 2),
+        ]));
+        let state = activities.clone();
+        let current = Arc::new(Mutex::new("a-1".to_string()));
+        bus.serve(STAND_IN_SERVICE, move |message: &Message| {
+            assert_eq!(message.path().as_deref(), Some(STAND_IN_PATH));
+            let mut activities = state.lock().unwrap();
+            let mut current = current.lock().unwrap();
+            let find =
+                |id: Option<String>| activities.iter().position(|a| Some(&a.0) == id.as_ref());
+            let reply = message.method_return();
+            Some(match message.member().as_deref() {
+                Some("ListActivities") => {
+                    reply.append1(activities.iter().map(|a| a.0.clone()).collect::<Vec<_>>())
+                }
+                Some("ListActivitiesWithInformation") => reply.append1(
+                    activities
+                        .iter()
+                        .map(|(id, name, state)| {
+                            (
+                                id.clone(),
+                                name.clone(),
+                                String::new(),
+                                String::new(),
+                                *state,
+                            )
+                        })
+                        .collect::<Vec<_>>(),
+                ),
+                Some("ActivityName") => match find(message.get1()) {
+                    Some(i) => reply.append1(activities[i].1.clone()),
+                    None => reply.append1(""),
+                },
+                Some("CurrentActivity") => reply.append1(current.clone()),
+                Some("SetCurrentActivity") => match find(message.get1()) {
+                    Some(i) => {
+                        *current = activities[i].0.clone();
+                        reply.append1(true)
+                    }
+                    None => reply.append1(false),
+                },
+                Some("AddActivity") => {
+                    let id = format!("a-{}", activities.len() + 1);
+                    activities.push((id.clone(), message.get1().unwrap(), 2));
+                    reply.append1(id)
+                }
+                Some("StopActivity") => {
+                    if let Some(i) = find(message.get1()) {
+                        activities[i].2 = 4;
+                    }
+                    reply
+                }
+                _ => return None,
+            })
+        });
+        activities
+    }
+
+    #[test]
+    fn activity_steps() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        let activities = stand_in(&bus);
+        let conn = bus.connect();
+        let proxy = conn.with_proxy(STAND_IN_SERVICE, STAND_IN_PATH, Duration::from_millis(5000));
+
+        assert_eq!(
+            NativeStep::GetActivity.run_with(&conn, &proxy).unwrap(),
+            json!({ "id": "a-1", "name": "Work" })
+        );
+        NativeStep::SetActivity {
+            activity: "Play".into(),
+        }
+        .run_with(&conn, &proxy)
+        .unwrap();
+        assert_eq!(current_activity(&proxy).unwrap(), "a-2");
+        assert!(NativeStep::SetActivity {
+            activity: "Sleep".into(),
+        }
+        .run_with(&conn, &proxy)
+        .is_err());
+
+        let id = NativeStep::CreateActivity { name: "New".into() }
+            .run_with(&conn, &proxy)
+            .unwrap();
+        assert_eq!(id, json!("a-3"));
+        NativeStep::StopActivity {
+            activity: "a-3".into(),
+        }
+        .run_with(&conn, &proxy)
+        .unwrap();
+        assert_eq!(activities.lock().unwrap()[2].2, 4);
+
+        assert_eq!(
+            NativeStep::ListActivities.run_with(&conn, &proxy).unwrap(),
+            json!([
+                { "id": "a-1", "name": "Work", "state": "running", "current": false },
+                { "id": "a-2", "name": "Play", "state": "running", "current": true },
+                { "id": "a-3", "name": "New", "state": "stopped", "current": false },
+            ])
+        );
+        assert_eq!(
+            list_activities(&proxy).unwrap(),
+            vec![
+                ("a-1".to_string(), "Work".to_string()),
+                ("a-2".to_string(), "Play".to_string()),
+                ("a-3".to_string(), "New".to_string()),
+            ]
+        );
+        assert_eq!(activity_id(&proxy, "current").unwrap(), "a-2");
+        assert_eq!(activity_id(&proxy, "Work").unwrap(), "a-1");
+    }
+}
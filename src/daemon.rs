@@ -0,0 +1,497 @@
+// `kdotool daemon` keeps one script loaded in KWin, and runs the commands of
+// other kdotool processes in it. This saves loading, running and stopping a new
+// script for each command.
+//
+// kdotool processes send the daemon their command chain, not a script, so that
+// other clients on the bus can't run arbitrary code in KWin. The daemon
+// generates the script, which reports back to the kdotool process as usual.
+//
+// The resident script polls the daemon for scripts to run. The daemon holds the
+// poll until it gets a script, or replies with an empty string after a while,
+// so that the call doesn't time out.
+//
+// The daemon also passes on the output of shortcut scripts registered with
+// `--on-output`.
+
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use dbus::{
+    blocking::{stdintf::org_freedesktop_dbus::RequestNameReply, Connection},
+    channel::{MatchingReceiver, Sender},
+    message::MatchRule,
+    Message,
+};
+
+use crate::output::route_registered_output;
+use crate::scripting::*;
+use crate::templates::DAEMON_SCRIPT;
+use crate::{generate_script, Globals, Parser};
+
+pub const DAEMON_NAME: &str = "io.github.jinliu.kdotool";
+const DAEMON_SCRIPT_NAME: &str = "kdotool-daemon";
+
+// The script's calls to the daemon time out after 25 seconds.
+const POLL_TIMEOUT: Duration = Duration::from_secs(20);
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(25);
+
+#[derive(Default)]
+struct State {
+    kde5: bool,
+    poll: Option<(Message, Instant)>,
+    last_poll: Option<Instant>,
+    queue: VecDeque<String>,
+    scripts: u64,
+}
+
+impl State {
+    fn script_alive(&self) -> bool {
+        self.poll.is_some() || self.last_poll.is_some_and(|t| t.elapsed() < SCRIPT_TIMEOUT)
+    }
+
+    // Generate the script for a "run" call.
+    fn generate(&mut self, message: &Message) -> anyhow::Result<String> {
+        let (reply_to, chain, window_stack, debug, physical_coords): (
+            String,
+            Vec<String>,
+            String,
+            bool,
+            bool,
+        ) = message.read5()?;
+        // The address is inserted into the script as it is.
+        if !is_unique_name(&reply_to) {
+            return Err(anyhow!("invalid address '{reply_to}'"));
+        }
+        let (command, args) = chain.split_first().ok_or(anyhow!("empty chain"))?;
+        self.scripts += 1;
+        let marker = format!("{DAEMON_SCRIPT_NAME}-{}", self.scripts);
+        let context = Globals {
+            dbus_addr: reply_to,
+            debug,
+            kde5: self.kde5,
+            physical_coords,
+            script_name: marker.clone(),
+            marker,
+            // The window stack is inserted into the script, so check that it's a
+            // list of IDs. An empty string means there is none to carry over.
+            window_stack: if window_stack.is_empty() {
+                None
+            } else {
+                let window_stack: Vec<String> = serde_json::from_str(&window_stack)?;
+                Some(serde_json::to_string(&window_stack)?)
+            },
+            ..Default::default()
+        };
+        let (script, _) = generate_script(&context, Parser::from_args(args), command)?;
+        Ok(script)
+    }
+}
+
+// A unique connection name, like ":1.42".
+fn is_unique_name(name: &str) -> bool {
+    name.strip_prefix(':').is_some_and(|name| {
+        name.split('.').count() > 1
+            && name.split('.').all(|part| {
+                !part.is_empty()
+                    && part
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            })
+    })
+}
+
+pub fn daemon_running(conn: &Connection) -> bool {
+    let proxy = conn.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        Duration::from_millis(5000),
+    );
+    let result: Result<(bool,), _> =
+        proxy.method_call("org.freedesktop.DBus", "NameHasOwner", (DAEMON_NAME,));
+    result.is_ok_and(|(running,)| running)
+}
+
+// Have the daemon run a chain, with the options of the script generated for it
+// in context. The script reports to context.dbus_addr.
+pub fn run_in_daemon(conn: &Connection, context: &Globals, chain: &[String]) -> anyhow::Result<()> {
+    let window_stack = context.window_stack.as_deref().unwrap_or_default();
+    let proxy = conn.with_proxy(DAEMON_NAME, "/", Duration::from_millis(5000));
+    let _: () = proxy.method_call(
+        DAEMON_NAME,
+        "run",
+        (
+            &context.dbus_addr,
+            chain,
+            window_stack,
+            context.debug,
+            context.physical_coords,
+        ),
+    )?;
+    Ok(())
+}
+
+// Answer the calls of kdotool processes and of the resident script on conn.
+fn serve_calls(conn: &Connection, kde5: bool) -> Arc<Mutex<State>> {
+    let state = Arc::new(Mutex::new(State {
+        kde5,
+        ..Default::default()
+    }));
+    let receiver_state = state.clone();
+    conn.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |message, conn| -> bool {
+            let mut state = receiver_state.lock().unwrap();
+            let reply = match message.member().as_deref() {
+                Some("next") => {
+                    state.last_poll = Some(Instant::now());
+                    if let Some(script) = state.queue.pop_front() {
+                        Some(message.method_return().append1(script))
+                    } else {
+                        state.poll = Some((message, Instant::now()));
+                        None
+                    }
+                }
+                Some("run") if !state.script_alive() => Some(message.error(
+                    &"org.freedesktop.DBus.Error.Failed".into(),
+                    c"The daemon's KWin script isn't running",
+                )),
+                Some("run") => match state.generate(&message) {
+                    Ok(script) => {
+                        if let Some((poll, _)) = state.poll.take() {
+                            _ = conn.send(poll.method_return().append1(script));
+                        } else {
+                            state.queue.push_back(script);
+                        }
+                        Some(message.method_return())
+                    }
+                    Err(err) => Some(message.error(
+                        &"org.freedesktop.DBus.Error.InvalidArgs".into(),
+                        &CString::new(format!("{err:#}")).unwrap_or_default(),
+                    )),
+                },
+                // Output of shortcut scripts registered with --on-output.
+                Some(msgtype @ ("result" | "error")) => {
+                    let (text, route, name) = message.get3::<String, String, String>();
+                    let result = match (text, route, name) {
+                        (Some(text), Some(route), Some(name)) => route_registered_output(
+                            conn, &route, &name, msgtype, &text,
+                        )
+                        .map_err(|err| anyhow!("Failed to pass on the output of {name}: {err}")),
+                        _ => Err(anyhow!("invalid {msgtype} call")),
+                    };
+                    Some(match result {
+                        Ok(()) => message.method_return(),
+                        Err(err) => {
+                            log::warn!("{err}");
+                            message.error(
+                                &"org.freedesktop.DBus.Error.AccessDenied".into(),
+                                &CString::new(err.to_string()).unwrap_or_default(),
+                            )
+                        }
+                    })
+                }
+                _ => Some(message.error(
+                    &"org.freedesktop.DBus.Error.UnknownMethod".into(),
+                    c"Unknown method",
+                )),
+            };
+            if let Some(reply) = reply {
+                _ = conn.send(reply);
+            }
+            true
+        }),
+    );
+    state
+}
+
+pub fn run_daemon(kwin_conn: &Connection, kde5: bool) -> anyhow::Result<()> {
+    let conn = Connection::new_session()?;
+    if conn.request_name(DAEMON_NAME, false, false, true)? != RequestNameReply::PrimaryOwner {
+        return Err(anyhow!("kdotool daemon is already running"));
+    }
+    let state = serve_calls(&conn, kde5);
+
+    // A previous daemon may have left its script behind.
+    if is_script_loaded(kwin_conn, DAEMON_SCRIPT_NAME)? {
+        unload_script(kwin_conn, DAEMON_SCRIPT_NAME)?;
+    }
+
+    let mut script_file = tempfile::NamedTempFile::with_prefix("kdotool-")?;
+    let script = handlebars::Handlebars::new().render_template(
+        DAEMON_SCRIPT,
+        &serde_json::json!({ "daemon_name": DAEMON_NAME }),
+    )?;
+    script_file.write_all(script.as_bytes())?;
+    let script_file_path = script_file.into_temp_path();
+    let (_, script_proxy) =
+        load_temporary_script(kwin_conn, &script_file_path, DAEMON_SCRIPT_NAME, kde5)?;
+    let _: () = script_proxy.method_call("org.kde.kwin.Script", "run", ())?;
+    log::info!("kdotool daemon is running");
+
+    loop {
+        conn.process(Duration::from_millis(1000))?;
+        let mut state = state.lock().unwrap();
+        if state
+            .poll
+            .as_ref()
+            .is_some_and(|(_, since)| since.elapsed() > POLL_TIMEOUT)
+        {
+            let (poll, _) = state.poll.take().unwrap();
+            _ = conn.send(poll.method_return().append1(""));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_bus::TestBus;
+
+    // Mocks just enough of KWin to run scripts in the resident script.
+    // Answers the daemon's polls with the given scripts, then answers the
+    // scripts' native steps, once all of them are waiting. Prints the calls
+    // the scripts made, and the globals they left behind.
+    const HARNESS: &str = r#"
+        let [daemon, scripts] = process.argv.slice(1).map(JSON.parse);
+        let calls = [];
+        let natives = [];
+        globalThis.print = () => {};
+        globalThis.workspace = {
+            windowList: () => [{ internalId: "{w}", resourceClass: "x", resourceName: "x", windowRole: "", caption: "x" }],
+        };
+        globalThis.callDBus = (service, path, iface, method, ...args) => {
+            let callback = typeof args[args.length - 1] == "function" ? args.pop() : null;
+            if (method == "next") {
+                if (scripts.length > 0) {
+                    setTimeout(() => callback(scripts.shift()), 0);
+                }
+            } else if (method == "native") {
+                natives.push(callback);
+            } else {
+                calls.push([service, method]);
+            }
+        };
+        new Function(daemon)();
+        setTimeout(() => {
+            natives.forEach((callback) => callback(JSON.stringify({ id: "a", name: "A" })));
+            let globals = Object.keys(globalThis).filter((k) => /^(workspace|window|output)_/.test(k));
+            console.log(JSON.stringify({ calls: calls, globals: globals }));
+        }, 100);
+    "#;
+
+    fn script_for(dbus_addr: &str, command: &str, args: &[&str]) -> String {
+        let globals = Globals {
+            dbus_addr: dbus_addr.into(),
+            ..Default::default()
+        };
+        generate_script(&globals, Parser::from_args(args), command)
+            .unwrap()
+            .0
+    }
+
+    // Scripts of two clients, which wait for a native step at the same time,
+    // each report to their own client.
+    #[test]
+    fn concurrent_scripts() {
+        let daemon = handlebars::Handlebars::new()
+            .render_template(DAEMON_SCRIPT, &json!({ "daemon_name": DAEMON_NAME }))
+            .unwrap();
+        // `search --screen` fails in KDE 6, so both scripts report an error
+        // after the native get_activity step.
+        let args = ["search", "--screen", "0", "x"];
+        let scripts = [
+            script_for(":1.1", "get_activity", &args),
+            script_for(":1.2", "get_activity", &args),
+        ];
+        let output = match Command::new("node")
+            .args(["-e", HARNESS])
+            .arg(json!(daemon).to_string())
+            .arg(json!(scripts).to_string())
+            .output()
+        {
+            Ok(output) => output,
+            Err(err) => {
+                eprintln!("Skipping test, can't run node: {err}");
+                return;
+            }
+        };
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let result: Value = serde_json::from_slice(&output.stdout).unwrap();
+        assert_eq!(result["globals"], json!([]));
+        let calls_to = |addr: &str| -> Vec<Value> {
+            let calls = result["calls"].as_array().unwrap();
+            calls
+                .iter()
+                .filter(|call| call[0] == addr)
+                .map(|call| call[1].clone())
+                .collect()
+        };
+        let expected = json!(["result", "error", "finished"]);
+        assert_eq!(calls_to(":1.1"), expected.as_array().unwrap().clone());
+        assert_eq!(calls_to(":1.2"), expected.as_array().unwrap().clone());
+    }
+
+    fn run(
+        conn: &Connection,
+        args: (&str, Vec<&str>, &str, bool, bool),
+    ) -> Result<(), dbus::Error> {
+        conn.with_proxy(DAEMON_NAME, "/", Duration::from_millis(5000))
+            .method_call(DAEMON_NAME, "run", args)
+    }
+
+    // Two clients sending chains at the same time each get a script which
+    // reports to them.
+    #[test]
+    fn concurrent_clients() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        let daemon_conn = bus.connect();
+        daemon_conn
+            .request_name(DAEMON_NAME, false, false, true)
+            .unwrap();
+        serve_calls(&daemon_conn, false);
+        bus.spawn(daemon_conn);
+
+        // A stand-in for the resident script.
+        let script_conn = bus.connect();
+        let poller = std::thread::spawn(move || {
+            let proxy = script_conn.with_proxy(DAEMON_NAME, "/", Duration::from_millis(5000));
+            let mut scripts = vec![];
+            for _ in 0..2 {
+                let (script,): (String,) = proxy.method_call(DAEMON_NAME, "next", ()).unwrap();
+                scripts.push(script);
+            }
+            scripts
+        });
+        std::thread::sleep(Duration::from_millis(200));
+
+        let clients: Vec<_> = [vec!["getactivewindow"], vec!["search", "x"]]
+            .into_iter()
+            .map(|chain| {
+                let conn = bus.connect();
+                std::thread::spawn(move || {
+                    let name = conn.unique_name().to_string();
+                    run(&conn, (&name, chain, "", false, false)).unwrap();
+                    name
+                })
+            })
+            .collect();
+        let names: Vec<String> = clients.into_iter().map(|c| c.join().unwrap()).collect();
+
+        let scripts = poller.join().unwrap();
+        for (name, step) in names.iter().zip(["STEP getactivewindow", "STEP search"]) {
+            let script = scripts.iter().find(|s| s.contains(step)).unwrap();
+            let addresses: Vec<&str> = script
+                .split("callDBus(\"")
+                .skip(1)
+                .map(|s| s.split('"').next().unwrap())
+                .collect();
+            assert!(!addresses.is_empty());
+            assert!(
+                addresses.iter().all(|a| a == name),
+                "{addresses:?} != {name}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_calls() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        let daemon_conn = bus.connect();
+        daemon_conn
+            .request_name(DAEMON_NAME, false, false, true)
+            .unwrap();
+        let state = serve_calls(&daemon_conn, false);
+        state.lock().unwrap().last_poll = Some(Instant::now());
+        bus.spawn(daemon_conn);
+
+        let conn = bus.connect();
+        let name = conn.unique_name().to_string();
+        let error = |args| run(&conn, args).unwrap_err().message().unwrap().to_string();
+        assert_eq!(
+            error((
+                "\"); evil(); (\"",
+                vec!["getactivewindow"],
+                "",
+                false,
+                false
+            )),
+            "invalid address '\"); evil(); (\"'"
+        );
+        assert_eq!(error((&name, vec![], "", false, false)), "empty chain");
+        assert!(error((&name, vec!["nosuchcommand"], "", false, false)).contains("nosuchcommand"));
+        run(&conn, (&name, vec!["getactivewindow"], "", false, false)).unwrap();
+        assert_eq!(state.lock().unwrap().queue.len(), 1);
+        assert!(error((
+            &name,
+            vec!["getactivewindow"],
+            "[\"{w}\"]); evil(); ([",
+            false,
+            false
+        ))
+        .contains("trailing characters"));
+
+        let result: Result<(), _> = conn
+            .with_proxy(DAEMON_NAME, "/", Duration::from_millis(5000))
+            .method_call(DAEMON_NAME, "eval", ("evil()",));
+        assert_eq!(
+            result.unwrap_err().name(),
+            Some("org.freedesktop.DBus.Error.UnknownMethod")
+        );
+    }
+
+    // An empty window stack is still reported back, so that a `serve` session
+    // can tell it from a chain which doesn't carry one over.
+    #[test]
+    fn empty_window_stack() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        let daemon_conn = bus.connect();
+        daemon_conn
+            .request_name(DAEMON_NAME, false, false, true)
+            .unwrap();
+        let state = serve_calls(&daemon_conn, false);
+        state.lock().unwrap().last_poll = Some(Instant::now());
+        bus.spawn(daemon_conn);
+
+        let conn = bus.connect();
+        let name = conn.unique_name().to_string();
+        for window_stack in ["", "[]"] {
+            run(
+                &conn,
+                (&name, vec!["getactivewindow"], window_stack, false, false),
+            )
+            .unwrap();
+        }
+        let queue = state.lock().unwrap().queue.clone();
+        let reports = |script: &String| script.contains("\"window_stack\", JSON.stringify");
+        assert!(!reports(&queue[0]));
+        assert!(reports(&queue[1]));
+    }
+
+    #[test]
+    fn unique_names() {
+        assert!(is_unique_name(":1.42"));
+        assert!(is_unique_name(":1.2.3"));
+        assert!(!is_unique_name("io.github.jinliu.kdotool"));
+        assert!(!is_unique_name(":1"));
+        assert!(!is_unique_name(":1."));
+        assert!(!is_unique_name(":1.2\""));
+    }
+}
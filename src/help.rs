@@ -17,12 +17,69 @@ Options:
     -d, --debug        Enable debug output
     -n, --dry-run      Don't actually run the script. Just print it to stdout.
 
-    --shortcut SHORTCUT [--name NAME]
+    --coords logical|physical
+        Coordinate mode for positions and sizes, both in arguments and in
+        output. 'logical' (the default) uses KWin's coordinates. With
+        'physical', sizes and offsets from the top-left corner of a screen are
+        in device pixels, i.e. scaled by the scale factor of that screen, while
+        the corner itself stays at its logical position. A window or area is
+        converted with the screen its top-left corner is on.
+
+    --shortcut SHORTCUT [--name NAME [--replace]]
         Register a shortcut to run the script.
         Optionally set a name for the shortcut, so you can remove it later.
+        --replace replaces an existing shortcut with the same name.
+        Fails if the key is already used by another global shortcut.
+
+    --screen-edge top|top-right|right|bottom-right|bottom|bottom-left|left|top-left
+        Like --shortcut, but run the script when the mouse is pushed against
+        a screen edge or corner.
+
+    --touch-edge top|right|bottom|left
+        Like --shortcut, but run the script when swiping in from a screen edge
+        on a touch screen.
+
+    --menu-entry TITLE
+        Like --shortcut, but add an entry to the window menu (Alt+F3), which
+        runs the script with the window of the menu as %1.
+
+    --on-output notify|log:FILE|exec:CMD
+        With --shortcut, pass on the output of the script when the shortcut is
+        triggered: as a desktop notification, appended to FILE, or to the shell
+        command CMD as $1. Needs `kdotool daemon` to be running.
 
     --remove NAME    Remove a previously registered shortcut.
 
+    --shortcut SHORTCUT --name NAME --install
+        Install the script as a KWin script package, so the shortcut is kept
+        when KWin restarts.
+
+    --uninstall NAME    Remove an installed shortcut script.
+
+    kdotool shortcuts apply [--dry-run] FILE
+        Register the shortcuts in a TOML file, and remove the other shortcuts
+        registered by kdotool. See the README for the file format.
+
+    --list-shortcuts [--json]
+        List the registered shortcuts. Those whose scripts are no longer loaded
+        in KWin are marked as stale.
+
+Maintenance:
+    kdotool gc
+        Unload the scripts left in KWin by kdotool processes that were killed.
+
+Daemon:
+    kdotool daemon
+        Keep a script loaded in KWin, and run the commands of other kdotool
+        invocations in it. This is faster than loading a new script for each
+        command. kdotool uses the daemon automatically when it's running,
+        except with --shortcut. It also passes on the output of shortcuts
+        registered with --on-output.
+
+    kdotool serve --socket PATH
+        Serve the commands as a JSON-RPC 2.0 API on a Unix socket. See the
+        README for the protocol.
+
 Window Query Commands:
     search [OPTIONS] PATTERN    
         Search for windows with titles, names, or classes matching a regular
@@ -48,9 +105,13 @@ Window Query Commands:
         --screen NUMBER (KDE 5 only)
             Select windows only on a specific screen. Default is to search all
             screens.
-        --desktop NUMBER
+        --desktop DESKTOP
             Only match windows on a certain desktop. The default is to search
-            all desktops.
+            all desktops. See below for how to specify DESKTOP.
+        --activity ACTIVITY
+            Only match windows on a certain activity. ACTIVITY can be an
+            activity name, ID, or 'current'. Windows on all activities always
+            match.
         --limit NUMBER
             Stop searching after finding NUMBER matching windows. The default
             is no search limit (which is equivalent to '--limit 0')
@@ -63,6 +124,10 @@ Window Query Commands:
     getactivewindow
         Select the currently active window.
 
+    selectwindow
+        Select a window by clicking on it. This can't be used with
+        --shortcut.
+
     getmouselocation [--shell]
         Outputs the x, y, screen, and window id of the mouse cursor.
         
@@ -91,10 +156,16 @@ Window Action Commands:
     getwindowclassname [WINDOW]
         Output the class name of a window.
 
-    getwindowgeometry [WINDOW]
+    getwindowgeometry [--frame|--client] [WINDOW]
         Output the geometry (location and position) of a window. The values
         include: x, y, width, height, and (KDE 5 only) screen number.
 
+        --frame
+            Use the geometry including window decorations. This is the
+            default.
+        --client
+            Use the geometry of the window contents, without decorations.
+
     getwindowid [WINDOW]
         Output the ID of a window.
 
@@ -106,31 +177,79 @@ Window Action Commands:
         Activate a window. If the window is on another desktop, we will switch
         to that desktop.
     
-    windowraise [WINDOW] (KDE 6 only)
-        Raise a window to the top of the window stack.
+    windowraise [WINDOW]
+        Raise a window to the top of the window stack. In KDE 5, this also
+        activates the window.
+
+    windowlower [WINDOW]
+        Lower a window to the bottom of the window stack. In KDE 6, this
+        raises the other normal windows on its desktop and activity instead.
+        In KDE 5, this briefly activates the window.
 
     windowminimize [WINDOW]
         Minimize a window.
 
+    windowunminimize [WINDOW]
+        Unminimize a window, without changing the stacking order.
+
+    windowrestore [WINDOW]
+        Unminimize and raise a window. With %@, windows are raised in their
+        original stacking order, which undoes 'windowminimize %@'.
+
     windowclose [WINDOW]
         Close a window.
 
-    windowsize [WINDOW] WIDTH HEIGHT
+    windowsize [OPTIONS] [WINDOW] WIDTH HEIGHT
         Resize a window. Percentages are valid for WIDTH and HEIGHT. They are
-        relative to the geometry of the screen the window is on.
+        relative to the work area of the screen the window is on, unless
+        changed with --area.
 
         If the given WIDTH is literally 'x', then the window's current width
         will be unchanged. The same applies for 'y' for HEIGHT.
 
-    windowmove [--relative] [WINDOW] X Y
+        --area virtual|output|workarea
+            The area percentages are relative to. 'virtual' is the whole
+            virtual screen spanning all monitors, 'output' is the screen the
+            window is on, and 'workarea' (the default) is that screen without
+            panels.
+        --clamp
+            Keep the resulting window inside the area.
+        --frame
+            WIDTH and HEIGHT include window decorations. This is the default.
+        --client
+            WIDTH and HEIGHT are the size of the window contents, without
+            decorations.
+
+    windowmove [OPTIONS] [WINDOW] X Y
         Move a window. Percentages are valid for X and Y. They are relative to
-        relative to the geometry of the screen the window is on.
+        the work area of the screen the window is on, unless changed with
+        --area.
+
+        With --output, X and Y are optional. If given, they are applied after
+        the window is moved to the new screen.
 
         If the given x coordinate is literally 'x', then the window's current
         x position will be unchanged. The same applies for 'y'.
 
         --relative
             Make movement relative to the current window position.
+        --area virtual|output|workarea
+            Same as in windowsize.
+        --clamp
+            Keep the resulting window inside the area.
+        --frame
+            X and Y are the position of the window decorations. This is the
+            default.
+        --client
+            X and Y are the position of the window contents.
+        --output NAME|INDEX|next|prev
+            Move the window to another screen, given by its name, its index
+            (starting from 0), or relative to the screen the window is on.
+            The window is shrunk if it doesn't fit on the new screen.
+        --keep-relative
+            With --output, keep the window's position and size proportional
+            to the area of the screen. Screens with different resolutions or
+            scale factors are handled, as all sizes are in logical pixels.
     
     windowstate [--add PROPERTY] [--remove PROPERTY] [--toggle PROPERTY] [WINDOW]
         Change a property on a window.
@@ -145,24 +264,160 @@ Window Action Commands:
         SHADED - rolls the window up
         DEMANDS_ATTENTION - marks window urgent or needing attention
 
-    get_desktop_for_window [WINDOW]
-        Output the desktop number that a window is on.
+    get_desktop_for_window [--name] [WINDOW]
+        Output the desktop numbers that a window is on, one per line. Outputs
+        'all_desktops' if the window is on all desktops.
 
-    set_desktop_for_window [WINDOW] NUMBER
-        Move a window to a different desktop.
+        --name
+            Output the desktop names instead.
+
+    set_desktop_for_window [--follow] [WINDOW] DESKTOP
+        Move a window to a different desktop. Use 'current_desktop' to refer
+        to the current desktop.
+
+        --follow
+            Switch to the desktop afterwards.
+
+    add_desktop_for_window [WINDOW] DESKTOP (KDE 6 only)
+        Show a window on another desktop, in addition to the desktops it is
+        already on.
+
+    remove_desktop_for_window [WINDOW] DESKTOP (KDE 6 only)
+        Remove a window from a desktop. A window on all desktops will be on
+        all other desktops afterwards.
+
+    get_activity_for_window [--name] [WINDOW]
+        Output the IDs of the activities that a window is on, one per line.
+        Outputs 'all_activities' if the window is on all activities.
+
+        --name
+            Output the activity names instead.
+
+    set_activity_for_window [--all-activities] [WINDOW] ACTIVITY
+        Move a window to an activity. ACTIVITY can be an activity name, ID,
+        or 'current'.
+
+        --all-activities
+            Show the window on all activities instead. ACTIVITY must be
+            omitted.
 
 Global Commands:
-    get_desktop
+
+    DESKTOP can be specified as:
+    N - the desktop number, starting from 1
+    name:NAME - the desktop with the given name
+    xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx - the desktop with the given UUID
+         (KDE 6 only)
+
+    get_desktop [--name]
         Output the current desktop number.
+
+        --name
+            Output the desktop name instead.
     
-    set_desktop <number>
-        Change the current desktop to <number>.
+    set_desktop [OPTIONS] DESKTOP
+        Change the current desktop to DESKTOP.
+
+        OPTIONS:
+        --relative
+            Use relative movements instead of absolute. DESKTOP must be a
+            number then. E.g. 'set_desktop --relative -1' switches to the
+            previous desktop.
+        --direction left|right|up|down
+            Switch to the neighboring desktop in the desktop grid. DESKTOP
+            is not needed in this case.
+        --wrap
+            With --relative or --direction, wrap around at the first and last
+            desktop (or at the edges of the grid). Otherwise stay there.
 
     get_num_desktops
         Output the current number of desktops.
 
-    set_num_desktops <number> (KDE 5 only)
-        Change the number of desktops to <number>.
+    showdesktop on|off|toggle
+        Show or hide the desktop, like the "Show Desktop" button.
+
+    set_num_desktops <number>
+        Change the number of desktops to <number>. Desktops are added or
+        removed at the end.
+
+    list_desktops [--json]
+        Output the number, name, UUID (KDE 6 only) and number of windows of
+        each desktop.
+
+        OPTIONS:
+        --json
+            Output a JSON array instead of one line per desktop.
+
+    desktop_create [--name NAME] [--position NUMBER]
+        Create a new desktop. By default, it is added after the last desktop.
+
+        OPTIONS:
+        --name NAME
+            Set the name of the new desktop.
+        --position NUMBER
+            The desktop number the new desktop will get.
+
+    desktop_remove DESKTOP
+        Remove a desktop.
+
+    desktop_rename DESKTOP NAME
+        Rename a desktop.
+
+    set_desktop_rows <number>
+        Change the number of rows in the desktop grid to <number>.
+
+    get_activity [--name]
+        Output the ID of the current activity.
+
+        OPTIONS:
+        --name
+            Output the activity name instead.
+
+    set_activity ACTIVITY
+        Switch to ACTIVITY, given by name or ID.
+
+    list_activities [--json]
+        Output the ID, state, name and number of windows of each activity,
+        and whether it is the current activity.
+
+        OPTIONS:
+        --json
+            Output a JSON array instead of one line per activity.
+
+    activity_create NAME
+        Create a new activity and output its ID.
+
+    activity_stop ACTIVITY
+        Stop an activity, given by name, ID or 'current'.
+
+    getdisplaygeometry [--shell] [--output NAME|INDEX|next|prev]
+        Output the width and height of the whole virtual screen, or of the
+        given screen.
+
+        OPTIONS:
+        --shell
+            output shell data you can eval.
+        --output NAME|INDEX|next|prev
+            Use the given screen. 'next' and 'prev' are relative to the active
+            screen.
+
+    getstackingorder [--json]
+        Output the IDs of all windows from bottom to top. Raising them in this
+        order restores the stacking order.
+
+        OPTIONS:
+        --json
+            Output a JSON array with the ID, class, name, and minimized, keep
+            above and keep below state of each window.
+
+    listoutputs [--json]
+        Output the name, geometry, work area and scale factor of each screen,
+        and whether it is the active screen or the one under the mouse
+        cursor. Scale factor is always 1 in KDE 5.
+
+        OPTIONS:
+        --json
+            Output a JSON array instead of one line per screen.
 "#
     );
 }
@@ -0,0 +1,117 @@
+// Installing shortcut scripts as KWin script packages, so that they are loaded
+// again when KWin starts. A package is a directory under
+// $XDG_DATA_HOME/kwin/scripts with a metadata.json and contents/code/main.js,
+// and is enabled by a "<name>Enabled" key in the [Plugins] group of kwinrc.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use dbus::blocking::Connection;
+use serde_json::json;
+
+use crate::scripting::*;
+
+fn scripts_dir() -> anyhow::Result<PathBuf> {
+    let data_home = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME").ok_or(anyhow!("HOME is not set"))?)
+            .join(".local")
+            .join("share"),
+    };
+    Ok(data_home.join("kwin").join("scripts"))
+}
+
+fn set_plugin_enabled(name: &str, enabled: bool, kde5: bool) -> anyhow::Result<()> {
+    let kwriteconfig = if kde5 {
+        "kwriteconfig5"
+    } else {
+        "kwriteconfig6"
+    };
+    let mut command = Command::new(kwriteconfig);
+    command.args(["--file", "kwinrc", "--group", "Plugins", "--key"]);
+    command.arg(format!("{name}Enabled"));
+    if enabled {
+        command.arg("true");
+    } else {
+        command.arg("--delete");
+    }
+    let status = command.status().map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => anyhow!(
+            "{kwriteconfig} wasn't found. It's needed to enable installed scripts, and comes with KDE Frameworks' KConfig."
+        ),
+        _ => anyhow!("Failed to run {kwriteconfig}: {err}"),
+    })?;
+    if !status.success() {
+        return Err(anyhow!("{kwriteconfig} failed: {status}"));
+    }
+    Ok(())
+}
+
+// Make KWin load the enabled scripts which aren't loaded yet.
+fn start_scripts(conn: &Connection) -> anyhow::Result<()> {
+    let proxy = conn.with_proxy("org.kde.KWin", "/Scripting", Duration::from_millis(5000));
+    let _: () = proxy.method_call("org.kde.kwin.Scripting", "start", ())?;
+    Ok(())
+}
+
+// Install a script as a package, enable it, and load it. Returns the package
+// directory.
+pub fn install_script(
+    conn: &Connection,
+    name: &str,
+    description: &str,
+    script: &str,
+    kde5: bool,
+) -> anyhow::Result<PathBuf> {
+    let package_dir = scripts_dir()?.join(name);
+    let reinstalling = package_dir.exists();
+    if !reinstalling && is_script_loaded(conn, name)? {
+        return Err(anyhow!(
+            "A script named '{name}' is already loaded. Please use `--remove` to remove it first."
+        ));
+    }
+
+    let code_dir = package_dir.join("contents").join("code");
+    std::fs::create_dir_all(&code_dir)?;
+    std::fs::write(code_dir.join("main.js"), script)?;
+    let metadata = json!({
+        "KPackageStructure": "KWin/Script",
+        "KPlugin": {
+            "Id": name,
+            "Name": name,
+            "Description": description,
+            "Version": env!("CARGO_PKG_VERSION"),
+            "EnabledByDefault": false,
+            "ServiceTypes": ["KWin/Script"],
+        },
+        "X-Plasma-API": "javascript",
+        "X-Plasma-MainScript": "code/main.js",
+    });
+    std::fs::write(
+        package_dir.join("metadata.json"),
+        serde_json::to_string_pretty(&metadata)?,
+    )?;
+
+    set_plugin_enabled(name, true, kde5)?;
+    // KWin only loads the new version after the old one is unloaded. The old
+    // one stays loaded if the new one couldn't be installed.
+    if reinstalling {
+        unload_script(conn, name)?;
+    }
+    start_scripts(conn)?;
+    Ok(package_dir)
+}
+
+// Disable, unload and remove an installed script.
+pub fn uninstall_script(conn: &Connection, name: &str, kde5: bool) -> anyhow::Result<()> {
+    let package_dir = scripts_dir()?.join(name);
+    if !package_dir.join("metadata.json").exists() {
+        return Err(anyhow!("No script named '{name}' is installed"));
+    }
+    set_plugin_enabled(name, false, kde5)?;
+    unload_script(conn, name)?;
+    std::fs::remove_dir_all(package_dir)?;
+    Ok(())
+}
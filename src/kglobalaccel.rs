@@ -0,0 +1,180 @@
+// Looking up global shortcuts via the org.kde.kglobalaccel DBus service, so that
+// a shortcut isn't registered for a key that's already bound to something else.
+// KWin would register it, but it would never be triggered.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use dbus::blocking::{Connection, Proxy};
+
+const KGLOBALACCEL: &str = "org.kde.KGlobalAccel";
+
+// Modifiers and keys from Qt's Qt::KeyboardModifier and Qt::Key.
+const MODIFIERS: &[(&str, i32)] = &[
+    ("shift", 0x02000000),
+    ("ctrl", 0x04000000),
+    ("control", 0x04000000),
+    ("alt", 0x08000000),
+    ("meta", 0x10000000),
+    ("super", 0x10000000),
+];
+
+const KEYS: &[(&str, i32)] = &[
+    ("esc", 0x01000000),
+    ("escape", 0x01000000),
+    ("tab", 0x01000001),
+    ("backspace", 0x01000003),
+    ("return", 0x01000004),
+    ("enter", 0x01000005),
+    ("ins", 0x01000006),
+    ("insert", 0x01000006),
+    ("del", 0x01000007),
+    ("delete", 0x01000007),
+    ("pause", 0x01000008),
+    ("print", 0x01000009),
+    ("home", 0x01000010),
+    ("end", 0x01000011),
+    ("left", 0x01000012),
+    ("up", 0x01000013),
+    ("right", 0x01000014),
+    ("down", 0x01000015),
+    ("pgup", 0x01000016),
+    ("pageup", 0x01000016),
+    ("pgdown", 0x01000017),
+    ("pagedown", 0x01000017),
+    ("space", 0x20),
+];
+
+// Qt's combined key code for a shortcut like "Meta+Shift+K", or None if the
+// key isn't known.
+fn key_code(shortcut: &str) -> Option<i32> {
+    let mut parts: Vec<&str> = shortcut.split('+').collect();
+    // "Ctrl++" is Ctrl and the plus key, and "+" the plus key alone.
+    if shortcut.ends_with("++") || shortcut == "+" {
+        parts.truncate(parts.len() - 2);
+        parts.push("+");
+    }
+    let (key, modifiers) = parts.split_last()?;
+    let mut code = 0;
+    for modifier in modifiers {
+        let modifier = modifier.to_lowercase();
+        code |= MODIFIERS.iter().find(|(name, _)| *name == modifier)?.1;
+    }
+    let lower = key.to_lowercase();
+    let mut chars = key.chars();
+    code |= if let Some((_, value)) = KEYS.iter().find(|(name, _)| *name == lower) {
+        *value
+    } else if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse::<i32>().ok()) {
+        // F1 to F35
+        if !(1..=35).contains(&n) {
+            return None;
+        }
+        0x01000030 + n - 1
+    } else if let (Some(c), None) = (chars.next(), chars.next()) {
+        // Qt uses the upper case for letters.
+        if !c.is_ascii_graphic() {
+            return None;
+        }
+        c.to_ascii_uppercase() as i32
+    } else {
+        return None;
+    };
+    Some(code)
+}
+
+// A QKeySequence as kglobalaccel marshals it, in KF5 and KF6 alike: a struct
+// of an array of 4 key codes, i.e. "(ai)", with the unused ones 0.
+fn key_sequence(code: i32) -> (Vec<i32>,) {
+    (vec![code, 0, 0, 0],)
+}
+
+fn kglobalaccel_proxy(conn: &Connection) -> Proxy<'_, &Connection> {
+    conn.with_proxy(
+        "org.kde.kglobalaccel",
+        "/kglobalaccel",
+        Duration::from_millis(5000),
+    )
+}
+
+// Fail if the key is bound to anything other than the KWin script shortcut
+// with the given name. If kglobalaccel can't be asked, the shortcut is
+// registered anyway.
+pub fn check_shortcut_key(conn: &Connection, shortcut: &str, name: &str) -> anyhow::Result<()> {
+    let Some(code) = key_code(shortcut) else {
+        log::debug!("Not checking unknown key '{shortcut}' for conflicts");
+        return Ok(());
+    };
+    let result: Result<(Vec<String>,), _> =
+        kglobalaccel_proxy(conn).method_call(KGLOBALACCEL, "actionList", (key_sequence(code),));
+    let action = match result {
+        Ok((action,)) => action,
+        Err(err) => {
+            log::warn!("Failed to check '{shortcut}' for conflicts: {err}");
+            return Ok(());
+        }
+    };
+    match action.as_slice() {
+        [component, action_name, component_name, action_text, ..]
+            if !(component == "kwin" && action_name == name) =>
+        {
+            Err(anyhow!(
+                "{shortcut} is already used by '{action_text}' in {component_name} ({component}/{action_name})"
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Remove the binding of a removed KWin script shortcut, so that its key can be
+// used again.
+pub fn unregister_shortcut_key(conn: &Connection, name: &str) {
+    let result: Result<(bool,), _> =
+        kglobalaccel_proxy(conn).method_call(KGLOBALACCEL, "unregister", ("kwin", name));
+    if let Err(err) = result {
+        log::debug!("Failed to unregister shortcut {name}: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::Message;
+
+    #[test]
+    fn key_codes() {
+        assert_eq!(
+            key_code("Meta+Shift+K"),
+            Some(0x10000000 | 0x02000000 | 0x4b)
+        );
+        assert_eq!(key_code("meta+k"), key_code("Super+K"));
+        assert_eq!(
+            key_code("Ctrl+Alt+Delete"),
+            Some(0x04000000 | 0x08000000 | 0x01000007)
+        );
+        assert_eq!(key_code("Ctrl++"), Some(0x04000000 | 0x2b));
+        assert_eq!(key_code("+"), Some(0x2b));
+        assert_eq!(key_code("F1"), Some(0x01000030));
+        assert_eq!(key_code("Shift+F35"), Some(0x02000000 | 0x01000052));
+        assert_eq!(key_code("Meta+Space"), Some(0x10000000 | 0x20));
+        assert_eq!(key_code("Meta+1"), Some(0x10000000 | 0x31));
+        assert_eq!(key_code("F36"), None);
+        assert_eq!(key_code("F0"), None);
+        assert_eq!(key_code("Hyper+K"), None);
+        assert_eq!(key_code("Meta+Menu"), None);
+        assert_eq!(key_code("Meta+é"), None);
+        assert_eq!(key_code(""), None);
+    }
+
+    #[test]
+    fn key_sequence_signature() {
+        let message = Message::new_method_call(
+            "org.kde.kglobalaccel",
+            "/kglobalaccel",
+            KGLOBALACCEL,
+            "actionList",
+        )
+        .unwrap()
+        .append1(key_sequence(0x4b));
+        assert_eq!(&*message.iter_init().signature(), "(ai)");
+    }
+}
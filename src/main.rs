@@ -7,37 +7,95 @@ use parser::*;
 mod help;
 use help::*;
 
+mod native;
+use native::*;
+
+mod activities;
+use activities::*;
+
+mod window_info;
+use window_info::*;
+
+mod scripting;
+use scripting::*;
+
+mod daemon;
+use daemon::*;
+
+mod serve;
+use serve::*;
+
+mod shortcuts;
+use shortcuts::*;
+
+mod install;
+use install::*;
+
+mod kglobalaccel;
+use kglobalaccel::*;
+
+mod output;
+use output::*;
+
+#[cfg(test)]
+mod test_bus;
+
 use std::io::Write;
+use std::path::Path;
 use std::process::Command;
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::time::Duration;
 
 use anyhow::{anyhow, Context};
 use dbus::{
     blocking::{Connection, SyncConnection},
-    channel::MatchingReceiver,
+    channel::{MatchingReceiver, Sender},
     message::MatchRule,
 };
 use serde::Serialize;
 
-#[derive(Default, Serialize)]
+#[derive(Clone, Default, Serialize)]
 struct Globals {
     dbus_addr: String,
     cmdline: String,
     debug: bool,
     kde5: bool,
     marker: String,
+    physical_coords: bool,
+    // Generate the script without looking anything up via D-Bus.
+    dry_run: bool,
     script_name: String,
+    // The script name as a JSON string, for registered scripts.
+    script_name_json: String,
     shortcut: String,
+    // The initial window stack as a JSON array of window IDs, for `serve`.
+    window_stack: Option<String>,
+    // The --on-output route of a shortcut, as a JSON string.
+    on_output: String,
+    // The script registers a shortcut or screen edges, instead of running.
+    registered: bool,
+    screen_edge: String,
+    touch_edge: String,
+    // The title of a window menu entry, as a JSON string.
+    menu_entry: String,
 }
 
 struct StepResult {
     script: String,
     is_query: bool,
     next_arg: Option<String>,
+    native: Option<NativeStep>,
 }
 
-static MESSAGES: RwLock<Vec<(String, String)>> = RwLock::new(vec![]);
+// (type, message) pairs sent by a script.
+type Messages = Vec<(String, String)>;
+
+enum Event {
+    Message(String, String),
+    Native(usize, dbus::Message),
+    Finished,
+}
 
 fn add_context<T>(render_context: &mut handlebars::Context, key: &str, value: T)
 where
@@ -54,10 +112,11 @@ fn generate_script(
     globals: &Globals,
     mut parser: Parser,
     next_arg: &str,
-) -> anyhow::Result<String> {
+) -> anyhow::Result<(String, Vec<NativeStep>)> {
     use lexopt::prelude::*;
 
     let mut full_script = String::new();
+    let mut native_steps = vec![];
     let mut reg = handlebars::Handlebars::new();
     reg.set_strict_mode(true);
     let render_context = handlebars::Context::wraps(globals)?;
@@ -73,6 +132,18 @@ fn generate_script(
         let step_result = generate_step(&command, &mut parser, &reg, &render_context, globals)
             .with_context(|| format!("in command '{command}'"))?;
 
+        if let Some(native) = step_result.native {
+            if globals.registered {
+                return Err(anyhow!(
+                    "'{command}' can't be used with --shortcut, screen edges or menu entries"
+                ));
+            }
+            let mut render_context = render_context.clone();
+            add_context(&mut render_context, "step_name", command.as_str());
+            add_context(&mut render_context, "native_index", native_steps.len());
+            full_script.push_str(&reg.render_template_with_context(STEP_NATIVE, &render_context)?);
+            native_steps.push(native);
+        }
         full_script.push_str(&step_result.script);
         last_step_is_query = step_result.is_query;
 
@@ -99,9 +170,13 @@ fn generate_script(
         full_script.push_str(&reg.render_template_with_context(STEP_LAST_OUTPUT, &render_context)?);
     }
 
+    full_script.push_str(&reg.render_template_with_context(STEP_FINISH, &render_context)?);
+    for _ in &native_steps {
+        full_script.push_str(&reg.render_template_with_context(STEP_NATIVE_END, &render_context)?);
+    }
     full_script.push_str(&reg.render_template_with_context(SCRIPT_FOOTER, &render_context)?);
 
-    Ok(full_script)
+    Ok((full_script, native_steps))
 }
 
 fn generate_step(
@@ -121,7 +196,7 @@ fn generate_step(
 
     match command {
         "search" => {
-            return step_search(parser, reg, &render_context);
+            return step_search(parser, reg, &render_context, globals);
         }
 
         "getactivewindow" => {
@@ -130,6 +205,24 @@ fn generate_step(
             is_query = true;
         }
 
+        "selectwindow" => {
+            return Ok(StepResult {
+                script: reg.render_template_with_context(STEP_SELECTWINDOW, &render_context)?,
+                is_query: true,
+                next_arg,
+                native: Some(NativeStep::SelectWindow),
+            });
+        }
+
+        "desktop_create" | "desktop_remove" | "desktop_rename" | "set_desktop_rows" => {
+            return step_desktop(command, parser, reg, &render_context, globals);
+        }
+
+        "get_activity" | "set_activity" | "list_activities" | "activity_create"
+        | "activity_stop" => {
+            return step_activity(command, parser, reg, &render_context);
+        }
+
         "savewindowstack" | "loadwindowstack" => {
             let mut arg_name = None;
             while let Some(arg) = parser.next()? {
@@ -150,7 +243,7 @@ fn generate_step(
             add_context(
                 &mut render_context,
                 "name",
-                arg_name.ok_or(anyhow!("missing argument 'name'"))?.as_str(),
+                serde_json::to_string(&arg_name.ok_or(anyhow!("missing argument 'name'"))?)?,
             );
             step_script = reg.render_template_with_context(
                 if command == "savewindowstack" {
@@ -226,6 +319,11 @@ fn generate_step(
 
                     "windowmove" | "windowsize" => {
                         let mut opt_relative = false;
+                        let mut opt_area = String::from("workarea");
+                        let mut opt_clamp = false;
+                        let mut opt_output: Option<String> = None;
+                        let mut opt_keep_relative = false;
+                        let mut opt_client = false;
                         let mut arg_x: Option<String> = None;
                         let mut arg_y: Option<String> = None;
 
@@ -234,14 +332,47 @@ fn generate_step(
                                 Long("relative") if command == "windowmove" => {
                                     opt_relative = true;
                                 }
+                                Long("area") => {
+                                    opt_area = parser.value()?.string()?;
+                                    if !AREAS.contains(&opt_area.as_str()) {
+                                        return Err(anyhow!("invalid area '{opt_area}'"));
+                                    }
+                                }
+                                Long("clamp") => {
+                                    opt_clamp = true;
+                                }
+                                Long("frame") => {
+                                    opt_client = false;
+                                }
+                                Long("client") => {
+                                    opt_client = true;
+                                }
+                                Long("output") if command == "windowmove" => {
+                                    opt_output = Some(parser.value()?.string()?);
+                                }
+                                Long("keep-relative") if command == "windowmove" => {
+                                    opt_keep_relative = true;
+                                }
                                 Value(val) if arg_window_id.is_none() && arg_x.is_none() => {
                                     let s = val.string()?;
                                     if let Some(id) = to_window_id(&s) {
                                         arg_window_id = Some(id);
+                                    } else if opt_output.is_some() && !is_coordinate(&s) {
+                                        next_arg = Some(s);
+                                        break;
                                     } else {
                                         arg_x = Some(s);
                                     }
                                 }
+                                Value(val) if arg_x.is_none() && opt_output.is_some() => {
+                                    let s = val.string()?;
+                                    if is_coordinate(&s) {
+                                        arg_x = Some(s);
+                                    } else {
+                                        next_arg = Some(s);
+                                        break;
+                                    }
+                                }
                                 Value(val) if arg_x.is_none() => {
                                     arg_x = Some(val.string()?);
                                 }
@@ -274,7 +405,7 @@ fn generate_step(
                                     x = arg;
                                 }
                             }
-                        } else {
+                        } else if opt_output.is_none() {
                             return Err(anyhow!("missing argument 'x'"));
                         }
 
@@ -289,12 +420,28 @@ fn generate_step(
                                     y = arg;
                                 }
                             }
-                        } else {
+                        } else if opt_output.is_none() || !(x.is_empty() && x_percent.is_empty()) {
                             return Err(anyhow!("missing argument 'y'"));
                         }
 
+                        if opt_keep_relative && opt_output.is_none() {
+                            return Err(anyhow!("--keep-relative requires --output"));
+                        }
+                        if opt_output.is_some() && opt_area == "virtual" {
+                            return Err(anyhow!("--area virtual can't be used with --output"));
+                        }
+
                         let mut render_context = render_context.clone();
                         add_context(&mut render_context, "relative", opt_relative);
+                        add_context(&mut render_context, "area", opt_area);
+                        add_context(&mut render_context, "clamp", opt_clamp);
+                        let output = match opt_output {
+                            Some(output) => serde_json::to_string(&output)?,
+                            None => String::new(),
+                        };
+                        add_context(&mut render_context, "output", output);
+                        add_context(&mut render_context, "keep_relative", opt_keep_relative);
+                        add_context(&mut render_context, "client", opt_client);
                         add_context(&mut render_context, "x", x);
                         add_context(&mut render_context, "y", y);
                         add_context(&mut render_context, "x_percent", x_percent);
@@ -305,10 +452,85 @@ fn generate_step(
                         )?;
                     }
 
-                    "set_desktop_for_window" => {
+                    "get_desktop_for_window" => {
+                        let mut opt_name = false;
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("name") => {
+                                    opt_name = true;
+                                }
+                                Value(val) if arg_window_id.is_none() => {
+                                    let s = val.string()?;
+                                    if let Some(id) = to_window_id(&s) {
+                                        arg_window_id = Some(id);
+                                    } else {
+                                        next_arg = Some(s);
+                                        break;
+                                    }
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        let mut render_context = render_context.clone();
+                        add_context(&mut render_context, "name", opt_name);
+                        action_script = reg.render_template_with_context(
+                            WINDOW_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "getwindowgeometry" => {
+                        let mut opt_client = false;
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("frame") => {
+                                    opt_client = false;
+                                }
+                                Long("client") => {
+                                    opt_client = true;
+                                }
+                                Value(val) if arg_window_id.is_none() => {
+                                    let s = val.string()?;
+                                    if let Some(id) = to_window_id(&s) {
+                                        arg_window_id = Some(id);
+                                    } else {
+                                        next_arg = Some(s);
+                                        break;
+                                    }
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        let mut render_context = render_context.clone();
+                        add_context(&mut render_context, "client", opt_client);
+                        action_script = reg.render_template_with_context(
+                            WINDOW_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "set_desktop_for_window"
+                    | "add_desktop_for_window"
+                    | "remove_desktop_for_window" => {
                         let mut arg_desktop_id: Option<String> = None;
+                        let mut opt_follow = false;
                         while let Some(arg) = next_maybe_num(parser)? {
                             match arg {
+                                Long("follow") if command == "set_desktop_for_window" => {
+                                    opt_follow = true;
+                                }
                                 Value(val)
                                     if arg_window_id.is_none() && arg_desktop_id.is_none() =>
                                 {
@@ -331,24 +553,108 @@ fn generate_step(
                                 }
                             }
                         }
-                        let desktop_id = match arg_desktop_id {
-                            Some(id) => {
-                                if let Ok(n) = id.parse::<i32>() {
-                                    if n >= 0 {
-                                        n
+                        let desktop_id =
+                            arg_desktop_id.ok_or(anyhow!("missing argument 'desktop_id'"))?;
+                        let mut render_context = render_context.clone();
+                        add_context(
+                            &mut render_context,
+                            "current",
+                            desktop_id.to_lowercase() == "current_desktop",
+                        );
+                        add_context(
+                            &mut render_context,
+                            "desktop",
+                            serde_json::to_string(&desktop_id)?,
+                        );
+                        add_context(&mut render_context, "follow", opt_follow);
+                        action_script = reg.render_template_with_context(
+                            WINDOW_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "get_activity_for_window" => {
+                        let mut opt_name = false;
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("name") => {
+                                    opt_name = true;
+                                }
+                                Value(val) if arg_window_id.is_none() => {
+                                    let s = val.string()?;
+                                    if let Some(id) = to_window_id(&s) {
+                                        arg_window_id = Some(id);
                                     } else {
-                                        return Err(anyhow!("invalid desktop id '{id}'"));
+                                        next_arg = Some(s);
+                                        break;
                                     }
-                                } else if id.to_lowercase() == "current_desktop" {
-                                    -1
-                                } else {
-                                    return Err(anyhow!("invalid desktop id '{id}'"));
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
                                 }
                             }
-                            None => return Err(anyhow!("missing argument 'desktop_id'")),
-                        };
+                        }
+                        let mut render_context = render_context.clone();
+                        add_context(&mut render_context, "name", opt_name);
+                        if opt_name {
+                            add_context(
+                                &mut render_context,
+                                "activity_names",
+                                activity_names_expr(globals.dry_run)?,
+                            );
+                        }
+                        action_script = reg.render_template_with_context(
+                            WINDOW_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "set_activity_for_window" => {
+                        let mut arg_activity: Option<String> = None;
+                        let mut opt_all_activities = false;
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("all-activities") => {
+                                    opt_all_activities = true;
+                                }
+                                Value(val) if arg_window_id.is_none() && arg_activity.is_none() => {
+                                    let s = val.string()?;
+                                    if let Some(id) = to_window_id(&s) {
+                                        arg_window_id = Some(id);
+                                    } else if opt_all_activities {
+                                        next_arg = Some(s);
+                                        break;
+                                    } else {
+                                        arg_activity = Some(s);
+                                    }
+                                }
+                                Value(val) if arg_activity.is_none() && !opt_all_activities => {
+                                    arg_activity = Some(val.string()?);
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
                         let mut render_context = render_context.clone();
-                        add_context(&mut render_context, "desktop_id", desktop_id);
+                        add_context(&mut render_context, "all_activities", opt_all_activities);
+                        if !opt_all_activities {
+                            let activity =
+                                arg_activity.ok_or(anyhow!("missing argument 'activity'"))?;
+                            add_context(
+                                &mut render_context,
+                                "activity",
+                                activity_expr(&activity, globals.dry_run)?,
+                            );
+                        }
                         action_script = reg.render_template_with_context(
                             WINDOW_ACTIONS.get(command).unwrap(),
                             &render_context,
@@ -388,6 +694,12 @@ fn generate_step(
                 add_context(&mut render_context, "action", action_script);
 
                 if window_id == "%@" {
+                    let mut render_context = render_context.clone();
+                    add_context(
+                        &mut render_context,
+                        "stacking_order",
+                        command == "windowrestore",
+                    );
                     step_script = reg
                         .render_template_with_context(STEP_ACTION_ON_STACK_ALL, &render_context)?;
                 } else if let Some(s) = window_id.strip_prefix('%') {
@@ -398,7 +710,11 @@ fn generate_step(
                         .render_template_with_context(STEP_ACTION_ON_STACK_ITEM, &render_context)?;
                 } else {
                     let mut render_context = render_context.clone();
-                    add_context(&mut render_context, "window_id", window_id);
+                    add_context(
+                        &mut render_context,
+                        "window_id",
+                        serde_json::to_string(&window_id)?,
+                    );
                     step_script = reg
                         .render_template_with_context(STEP_ACTION_ON_WINDOW_ID, &render_context)?;
                 }
@@ -406,11 +722,26 @@ fn generate_step(
                 let action_script;
                 match command {
                     "set_desktop" | "set_num_desktops" => {
-                        let mut arg_n: Option<i32> = None;
+                        let mut arg_n: Option<String> = None;
+                        let mut opt_relative = false;
+                        let mut opt_wrap = false;
+                        let mut opt_direction = String::new();
                         while let Some(arg) = next_maybe_num(parser)? {
                             match arg {
-                                Value(val) if arg_n.is_none() => {
-                                    arg_n = Some(val.parse()?);
+                                Long("relative") if command == "set_desktop" => {
+                                    opt_relative = true;
+                                }
+                                Long("wrap") if command == "set_desktop" => {
+                                    opt_wrap = true;
+                                }
+                                Long("direction") if command == "set_desktop" => {
+                                    opt_direction = parser.value()?.string()?;
+                                    if !DIRECTIONS.contains(&opt_direction.as_str()) {
+                                        return Err(anyhow!("invalid direction '{opt_direction}'"));
+                                    }
+                                }
+                                Value(val) if arg_n.is_none() && opt_direction.is_empty() => {
+                                    arg_n = Some(val.string()?);
                                 }
                                 Value(val) => {
                                     next_arg = Some(val.string()?);
@@ -422,9 +753,29 @@ fn generate_step(
                             }
                         }
 
-                        if let Some(n) = arg_n {
+                        if opt_relative && !opt_direction.is_empty() {
+                            return Err(anyhow!("--relative can't be used with --direction"));
+                        }
+
+                        if arg_n.is_some() || !opt_direction.is_empty() {
+                            let arg_n = arg_n.unwrap_or_default();
                             let mut render_context = render_context.clone();
-                            add_context(&mut render_context, "n", n);
+                            if command == "set_desktop" && !opt_relative {
+                                // A desktop number, name or UUID.
+                                add_context(&mut render_context, "n", 0);
+                                add_context(
+                                    &mut render_context,
+                                    "desktop",
+                                    serde_json::to_string(&arg_n)?,
+                                );
+                            } else if !arg_n.is_empty() {
+                                add_context(&mut render_context, "n", arg_n.parse::<i32>()?);
+                            } else {
+                                add_context(&mut render_context, "n", 0);
+                            }
+                            add_context(&mut render_context, "relative", opt_relative);
+                            add_context(&mut render_context, "wrap", opt_wrap);
+                            add_context(&mut render_context, "direction", opt_direction);
                             action_script = reg.render_template_with_context(
                                 GLOBAL_ACTIONS.get(command).unwrap(),
                                 &render_context,
@@ -436,6 +787,108 @@ fn generate_step(
                         }
                     }
 
+                    "get_desktop" => {
+                        let mut opt_name = false;
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("name") => {
+                                    opt_name = true;
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        add_context(&mut render_context, "name", opt_name);
+                        action_script = reg.render_template_with_context(
+                            GLOBAL_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "showdesktop" => {
+                        let mut arg_state: Option<String> = None;
+                        while let Some(arg) = parser.next()? {
+                            match arg {
+                                Value(val) if arg_state.is_none() => {
+                                    arg_state = Some(val.string()?);
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        let state = arg_state.ok_or(anyhow!("missing argument 'state'"))?;
+                        if !["on", "off", "toggle"].contains(&state.as_str()) {
+                            return Err(anyhow!("invalid state '{state}'"));
+                        }
+                        add_context(&mut render_context, "toggle", state == "toggle");
+                        add_context(&mut render_context, "on", state == "on");
+                        action_script = reg.render_template_with_context(
+                            GLOBAL_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "getdisplaygeometry" => {
+                        let mut opt_shell = false;
+                        let mut opt_output = String::new();
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("shell") => {
+                                    opt_shell = true;
+                                }
+                                Long("output") => {
+                                    opt_output = serde_json::to_string(&parser.value()?.string()?)?;
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        add_context(&mut render_context, "shell", opt_shell);
+                        add_context(&mut render_context, "output", opt_output);
+                        action_script = reg.render_template_with_context(
+                            GLOBAL_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
+                    "listoutputs" | "list_desktops" | "getstackingorder" => {
+                        let mut opt_json = false;
+                        while let Some(arg) = next_maybe_num(parser)? {
+                            match arg {
+                                Long("json") => {
+                                    opt_json = true;
+                                }
+                                Value(val) => {
+                                    next_arg = Some(val.string()?);
+                                    break;
+                                }
+                                _ => {
+                                    return Err(arg.unexpected().into());
+                                }
+                            }
+                        }
+                        add_context(&mut render_context, "json", opt_json);
+                        action_script = reg.render_template_with_context(
+                            GLOBAL_ACTIONS.get(command).unwrap(),
+                            &render_context,
+                        )?;
+                    }
+
                     "getmouselocation" => {
                         if globals.kde5 {
                             return Err(anyhow!("'getmouselocation' is not supported in KDE 5"));
@@ -485,6 +938,209 @@ fn generate_step(
         script: step_script,
         is_query,
         next_arg,
+        native: None,
+    })
+}
+
+fn step_desktop(
+    command: &str,
+    parser: &mut Parser,
+    reg: &handlebars::Handlebars,
+    render_context: &handlebars::Context,
+    globals: &Globals,
+) -> anyhow::Result<StepResult> {
+    use lexopt::prelude::*;
+
+    let mut opt_name: Option<String> = None;
+    let mut opt_position: Option<u32> = None;
+    let mut args: Vec<String> = vec![];
+    let num_args = match command {
+        "desktop_create" => 0,
+        "desktop_rename" => 2,
+        _ => 1,
+    };
+
+    let mut next_arg = None;
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Long("name") if command == "desktop_create" => {
+                opt_name = Some(parser.value()?.string()?);
+            }
+            Long("position") if command == "desktop_create" => {
+                let position: u32 = parser.value()?.parse()?;
+                if position == 0 {
+                    return Err(anyhow!("invalid position '{position}'"));
+                }
+                opt_position = Some(position);
+            }
+            Value(val) if args.len() < num_args => {
+                args.push(val.string()?);
+            }
+            Value(val) => {
+                next_arg = Some(val.string()?);
+                break;
+            }
+            _ => {
+                return Err(arg.unexpected().into());
+            }
+        }
+    }
+    if args.len() < num_args {
+        return Err(anyhow!(
+            "missing argument '{}'",
+            match (command, args.len()) {
+                ("set_desktop_rows", _) => "rows",
+                (_, 0) => "desktop_id",
+                _ => "name",
+            }
+        ));
+    }
+
+    let number = |s: &str| -> anyhow::Result<u32> {
+        match s.parse::<u32>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(anyhow!("invalid number '{s}'")),
+        }
+    };
+
+    let mut script = String::new();
+    let mut native = None;
+    if globals.kde5 || command == "set_desktop_rows" {
+        native = Some(match command {
+            "desktop_create" => NativeStep::CreateDesktop {
+                position: opt_position,
+                name: opt_name.unwrap_or_default(),
+            },
+            "desktop_remove" => NativeStep::RemoveDesktop {
+                desktop: args[0].clone(),
+            },
+            "desktop_rename" => NativeStep::RenameDesktop {
+                desktop: args[0].clone(),
+                name: args[1].clone(),
+            },
+            _ => NativeStep::SetDesktopRows {
+                rows: number(&args[0])?,
+            },
+        });
+    } else {
+        let mut render_context = render_context.clone();
+        match command {
+            "desktop_create" => {
+                add_context(&mut render_context, "position", opt_position.unwrap_or(0));
+                add_context(
+                    &mut render_context,
+                    "name",
+                    serde_json::to_string(&opt_name.unwrap_or_default())?,
+                );
+            }
+            "desktop_remove" => {
+                add_context(
+                    &mut render_context,
+                    "desktop",
+                    serde_json::to_string(&args[0])?,
+                );
+            }
+            _ => {
+                add_context(
+                    &mut render_context,
+                    "desktop",
+                    serde_json::to_string(&args[0])?,
+                );
+                add_context(
+                    &mut render_context,
+                    "name",
+                    serde_json::to_string(&args[1])?,
+                );
+            }
+        }
+        let action = reg
+            .render_template_with_context(GLOBAL_ACTIONS.get(command).unwrap(), &render_context)?;
+        add_context(&mut render_context, "action", action);
+        script = reg.render_template_with_context(STEP_GLOBAL_ACTION, &render_context)?;
+    }
+
+    Ok(StepResult {
+        script,
+        is_query: false,
+        next_arg,
+        native,
+    })
+}
+
+// Activities are managed via DBus by kdotool. The script only outputs the
+// results.
+fn step_activity(
+    command: &str,
+    parser: &mut Parser,
+    reg: &handlebars::Handlebars,
+    render_context: &handlebars::Context,
+) -> anyhow::Result<StepResult> {
+    use lexopt::prelude::*;
+
+    let mut opt_name = false;
+    let mut opt_json = false;
+    let mut arg: Option<String> = None;
+    let num_args = match command {
+        "get_activity" | "list_activities" => 0,
+        _ => 1,
+    };
+
+    let mut next_arg = None;
+    while let Some(a) = parser.next()? {
+        match a {
+            Long("name") if command == "get_activity" => {
+                opt_name = true;
+            }
+            Long("json") if command == "list_activities" => {
+                opt_json = true;
+            }
+            Value(val) if num_args > 0 && arg.is_none() => {
+                arg = Some(val.string()?);
+            }
+            Value(val) => {
+                next_arg = Some(val.string()?);
+                break;
+            }
+            _ => {
+                return Err(a.unexpected().into());
+            }
+        }
+    }
+    if num_args > 0 && arg.is_none() {
+        return Err(anyhow!(
+            "missing argument '{}'",
+            if command == "activity_create" {
+                "name"
+            } else {
+                "activity"
+            }
+        ));
+    }
+
+    let native = match command {
+        "get_activity" => NativeStep::GetActivity,
+        "set_activity" => NativeStep::SetActivity {
+            activity: arg.unwrap(),
+        },
+        "list_activities" => NativeStep::ListActivities,
+        "activity_create" => NativeStep::CreateActivity { name: arg.unwrap() },
+        _ => NativeStep::StopActivity {
+            activity: arg.unwrap(),
+        },
+    };
+
+    let mut render_context = render_context.clone();
+    add_context(&mut render_context, "name", opt_name);
+    add_context(&mut render_context, "json", opt_json);
+    let action =
+        reg.render_template_with_context(GLOBAL_ACTIONS.get(command).unwrap(), &render_context)?;
+    add_context(&mut render_context, "action", action);
+
+    Ok(StepResult {
+        script: reg.render_template_with_context(STEP_GLOBAL_ACTION, &render_context)?,
+        is_query: false,
+        next_arg,
+        native: Some(native),
     })
 }
 
@@ -492,6 +1148,7 @@ fn step_search(
     parser: &mut Parser,
     reg: &handlebars::Handlebars,
     render_context: &handlebars::Context,
+    globals: &Globals,
 ) -> anyhow::Result<StepResult> {
     use lexopt::prelude::*;
 
@@ -506,7 +1163,9 @@ fn step_search(
         match_pid: bool,
         pid: i32,
         match_desktop: bool,
-        desktop: i32,
+        desktop: String,
+        match_activity: bool,
+        activity: String,
         match_screen: bool,
         screen: i32,
         limit: u32,
@@ -555,7 +1214,11 @@ fn step_search(
             }
             Long("desktop") => {
                 opt.match_desktop = true;
-                opt.desktop = parser.value()?.parse()?;
+                opt.desktop = serde_json::to_string(&parser.value()?.string()?)?;
+            }
+            Long("activity") => {
+                opt.match_activity = true;
+                opt.activity = activity_expr(&parser.value()?.string()?, globals.dry_run)?;
             }
             Long("screen") => {
                 opt.match_screen = true;
@@ -588,14 +1251,276 @@ fn step_search(
         opt.match_role = true;
         opt.match_name = true;
     }
+    opt.search_term = serde_json::to_string(&opt.search_term)?;
     let render_context = handlebars::Context::wraps(opt)?;
     Ok(StepResult {
         script: reg.render_template_with_context(STEP_SEARCH, &render_context)?,
         is_query: true,
         next_arg,
+        native: None,
     })
 }
 
+// Create the file for a new script. Its name is used as the marker in the
+// script's output, and as the default script name.
+fn create_script_file(context: &mut Globals) -> anyhow::Result<tempfile::NamedTempFile> {
+    let script_file = tempfile::NamedTempFile::with_prefix("kdotool-")?;
+    context.marker = script_file
+        .path()
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .into();
+    if context.script_name.is_empty() {
+        context.script_name.clone_from(&context.marker);
+    }
+    context.script_name_json = serde_json::to_string(&context.script_name)?;
+    Ok(script_file)
+}
+
+// Cleans up after run_script() on every path, also on errors: stops the thread
+// receiving messages from the script, and unloads the temporary script, if
+// one was loaded.
+struct ScriptCleanup<'a> {
+    kwin_conn: &'a Connection,
+    done: Arc<AtomicBool>,
+    script_name: Option<String>,
+}
+
+impl Drop for ScriptCleanup<'_> {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        if let Some(name) = &self.script_name {
+            if let Err(err) = unload_script(self.kwin_conn, name) {
+                log::warn!("Failed to unload {name}: {err}");
+            }
+        }
+    }
+}
+
+// Run a generated script, and collect the messages it sends to self_conn.
+// chain is the command chain the script was generated from, which the daemon
+// generates the script from itself. Returns the script ID if the script was
+// loaded into KWin, instead of being run by the daemon.
+fn run_script(
+    kwin_conn: &Connection,
+    self_conn: Arc<SyncConnection>,
+    context: &Globals,
+    chain: &[String],
+    script_file_path: &Path,
+    native_steps: &[NativeStep],
+) -> anyhow::Result<(Option<i32>, Messages)> {
+    // setup message receiver
+    let (sender, receiver) = mpsc::channel();
+    let receiver_conn = self_conn.clone();
+    let mut cleanup = ScriptCleanup {
+        kwin_conn,
+        done: Arc::new(AtomicBool::new(false)),
+        script_name: None,
+    };
+    let receiver_done = cleanup.done.clone();
+    std::thread::spawn(move || {
+        let _receiver = receiver_conn.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, _connection| -> bool {
+                log::debug!("dbus message: {:?}", message);
+                if let Some(member) = message.member() {
+                    if let Some(arg) = message.get1::<String>() {
+                        let event = match &*member {
+                            "native" => Event::Native(arg.parse().unwrap_or(usize::MAX), message),
+                            "finished" => Event::Finished,
+                            _ => Event::Message(member.to_string(), arg),
+                        };
+                        _ = sender.send(event);
+                    }
+                }
+                true
+            }),
+        );
+        while !receiver_done.load(Ordering::Relaxed) {
+            receiver_conn.process(Duration::from_millis(1000)).unwrap();
+        }
+    });
+
+    // Use the resident script of `kdotool daemon` if it's running.
+    let in_daemon = !context.registered
+        && daemon_running(kwin_conn)
+        && match run_in_daemon(kwin_conn, context, chain) {
+            Ok(()) => {
+                log::debug!("===== Script is run by kdotool daemon =====");
+                true
+            }
+            Err(err) => {
+                log::debug!("Failed to run script via kdotool daemon: {err}");
+                false
+            }
+        };
+
+    let script = if in_daemon {
+        None
+    } else {
+        log::debug!("===== Load script into KWin =====");
+        let (script_id, script_proxy) = if !context.registered {
+            cleanup.script_name = Some(context.script_name.clone());
+            load_temporary_script(
+                kwin_conn,
+                script_file_path,
+                &context.script_name,
+                context.kde5,
+            )?
+        } else {
+            load_script(
+                kwin_conn,
+                script_file_path,
+                &context.script_name,
+                context.kde5,
+            )?
+        };
+
+        log::debug!("Script ID: {script_id}");
+        log::debug!("Script name: {}", context.script_name);
+
+        log::debug!("===== Run script =====");
+        let _: () = script_proxy.method_call("org.kde.kwin.Script", "run", ())?;
+        Some(script_id)
+    };
+
+    // The script may wait for a native step between two messages.
+    let timeout = Duration::from_millis(5000)
+        + native_steps
+            .iter()
+            .map(NativeStep::timeout)
+            .max()
+            .unwrap_or_default();
+    let mut messages = vec![];
+    if !context.registered {
+        loop {
+            match receiver.recv_timeout(timeout) {
+                Ok(Event::Message(msgtype, message)) => {
+                    messages.push((msgtype, message));
+                }
+                Ok(Event::Native(index, call)) => {
+                    let step = native_steps
+                        .get(index)
+                        .ok_or(anyhow!("invalid native step {index}"))?;
+                    let reply = match step.run(kwin_conn) {
+                        Ok(value) => value.to_string(),
+                        Err(err) => {
+                            messages.push(("error".into(), err.to_string()));
+                            String::new()
+                        }
+                    };
+                    self_conn
+                        .send(call.method_return().append1(reply))
+                        .map_err(|_| anyhow!("Failed to reply to the script"))?;
+                }
+                Ok(Event::Finished) => {
+                    break;
+                }
+                Err(_) => {
+                    messages.push((
+                        "error".into(),
+                        "Timed out waiting for the script to finish".into(),
+                    ));
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok((script, messages))
+}
+
+// The KWin.ElectricBorder of a screen edge.
+fn screen_edge_expr(edge: &str, touch: bool) -> anyhow::Result<String> {
+    SCREEN_EDGES
+        .get(edge)
+        .filter(|_| !touch || !edge.contains('-'))
+        .map(|expr| expr.to_string())
+        .ok_or(anyhow!(
+            "invalid {} edge '{edge}'",
+            if touch { "touch" } else { "screen" }
+        ))
+}
+
+// Make the script register the shortcut and the edges of an entry, instead of
+// running right away.
+fn set_triggers(context: &mut Globals, entry: &Shortcut) -> anyhow::Result<()> {
+    context.shortcut.clone_from(&entry.shortcut);
+    if let Some(edge) = &entry.screen_edge {
+        context.screen_edge = screen_edge_expr(edge, false)?;
+    }
+    if let Some(edge) = &entry.touch_edge {
+        context.touch_edge = screen_edge_expr(edge, true)?;
+    }
+    if let Some(title) = &entry.menu_entry {
+        context.menu_entry = serde_json::to_string(title)?;
+    }
+    context.registered = !entry.triggers().is_empty();
+    Ok(())
+}
+
+fn print_triggers(entry: &Shortcut, action: &str) {
+    if !entry.shortcut.is_empty() {
+        println!("Shortcut {action}: {}", entry.shortcut);
+    }
+    if let Some(edge) = &entry.screen_edge {
+        println!("Screen edge {action}: {edge}");
+    }
+    if let Some(edge) = &entry.touch_edge {
+        println!("Touch edge {action}: {edge}");
+    }
+    if let Some(title) = &entry.menu_entry {
+        println!("Menu entry {action}: {title}");
+    }
+}
+
+// Register a shortcut for a chain, as with `--shortcut KEY --name NAME`, and
+// record it. Returns the script ID.
+fn register_shortcut(
+    kwin_conn: &Connection,
+    globals: &Globals,
+    entry: &Shortcut,
+) -> anyhow::Result<i32> {
+    let (name, chain) = (&entry.name, &entry.chain);
+    let (command, args) = chain.split_first().ok_or(anyhow!("empty chain"))?;
+    let self_conn = Arc::new(SyncConnection::new_session()?);
+    if !entry.shortcut.is_empty() {
+        check_shortcut_key(kwin_conn, &entry.shortcut, name)?;
+    }
+    let mut context = Globals {
+        dbus_addr: if globals.on_output.is_empty() {
+            self_conn.unique_name().to_string()
+        } else {
+            DAEMON_NAME.into()
+        },
+        cmdline: format!("kdotool {}", chain.join(" ")),
+        script_name: name.into(),
+        ..globals.clone()
+    };
+    set_triggers(&mut context, entry)?;
+    let mut script_file = create_script_file(&mut context)?;
+    let (script_contents, native_steps) =
+        generate_script(&context, Parser::from_args(args), command)?;
+    script_file.write_all(script_contents.as_bytes())?;
+    let script_file_path = script_file.into_temp_path();
+    let (script_id, _) = run_script(
+        kwin_conn,
+        self_conn,
+        &context,
+        chain,
+        &script_file_path,
+        &native_steps,
+    )?;
+    let script_id = script_id.unwrap();
+    record_shortcut(Shortcut {
+        script_id: Some(script_id),
+        installed: false,
+        ..entry.clone()
+    })?;
+    Ok(script_id)
+}
+
 fn main() -> anyhow::Result<()> {
     let mut context = Globals {
         cmdline: std::env::args().collect::<Vec<String>>().join(" "),
@@ -614,8 +1539,16 @@ fn main() -> anyhow::Result<()> {
     let mut next_arg: Option<String> = None;
     let mut opt_help = false;
     let mut opt_version = false;
-    let mut opt_dry_run = false;
     let mut opt_remove = false;
+    let mut opt_list_shortcuts = false;
+    let mut opt_json = false;
+    let mut opt_install = false;
+    let mut opt_uninstall = None;
+    let mut opt_replace = false;
+    let mut opt_screen_edge = None;
+    let mut opt_touch_edge = None;
+    let mut opt_menu_entry = None;
+    let mut opt_on_output = None;
 
     while let Some(arg) = parser.next()? {
         use lexopt::prelude::*;
@@ -630,7 +1563,15 @@ fn main() -> anyhow::Result<()> {
                 context.debug = true;
             }
             Short('n') | Long("dry-run") => {
-                opt_dry_run = true;
+                context.dry_run = true;
+            }
+            Long("coords") => {
+                let coords = parser.value()?.string()?;
+                context.physical_coords = match coords.as_str() {
+                    "logical" => false,
+                    "physical" => true,
+                    _ => return Err(anyhow!("invalid coordinate mode '{coords}'")),
+                };
             }
             Long("shortcut") => {
                 context.shortcut = parser.value()?.string()?;
@@ -642,6 +1583,35 @@ fn main() -> anyhow::Result<()> {
                 opt_remove = true;
                 context.script_name = parser.value()?.string()?;
             }
+            Long("install") => {
+                opt_install = true;
+            }
+            Long("uninstall") => {
+                opt_uninstall = Some(parser.value()?.string()?);
+            }
+            Long("screen-edge") => {
+                opt_screen_edge = Some(parser.value()?.string()?);
+            }
+            Long("touch-edge") => {
+                opt_touch_edge = Some(parser.value()?.string()?);
+            }
+            Long("menu-entry") => {
+                opt_menu_entry = Some(parser.value()?.string()?);
+            }
+            Long("on-output") => {
+                let route = parse_output_route(&parser.value()?.string()?)?;
+                context.on_output = serde_json::to_string(&route)?;
+                opt_on_output = Some(route);
+            }
+            Long("replace") => {
+                opt_replace = true;
+            }
+            Long("list-shortcuts") => {
+                opt_list_shortcuts = true;
+            }
+            Long("json") => {
+                opt_json = true;
+            }
             Value(os_string) => {
                 next_arg = Some(os_string.string()?);
                 break;
@@ -652,7 +1622,9 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
-    if !opt_remove && next_arg.is_none() || opt_help {
+    if !opt_remove && !opt_list_shortcuts && opt_uninstall.is_none() && next_arg.is_none()
+        || opt_help
+    {
         help();
         return Ok(());
     }
@@ -674,96 +1646,182 @@ fn main() -> anyhow::Result<()> {
         .init();
 
     let kwin_conn = Connection::new_session()?;
-    let kwin_proxy =
-        kwin_conn.with_proxy("org.kde.KWin", "/Scripting", Duration::from_millis(5000));
+
+    ctrlc::set_handler(|| {
+        if let Ok(conn) = Connection::new_session() {
+            unload_temporary_scripts(&conn);
+        }
+        std::process::exit(130);
+    })?;
 
     if opt_remove {
-        let _: () = kwin_proxy.method_call(
-            "org.kde.kwin.Scripting",
-            "unloadScript",
-            (&context.script_name,),
-        )?;
+        remove_shortcut(&kwin_conn, &context.script_name)?;
         return Ok(());
     }
 
-    let self_conn = SyncConnection::new_session()?;
-    context.dbus_addr = self_conn.unique_name().to_string();
+    if opt_list_shortcuts {
+        return list_shortcuts(&kwin_conn, opt_json);
+    }
 
-    log::debug!("===== Generate KWin script =====");
-    let mut script_file = tempfile::NamedTempFile::with_prefix("kdotool-")?;
-    context.marker = script_file
-        .path()
-        .file_name()
-        .unwrap()
-        .to_string_lossy()
-        .into();
-    if context.script_name.is_empty() {
-        context.script_name.clone_from(&context.marker);
+    if let Some(name) = opt_uninstall {
+        // This already unloads the script.
+        uninstall_script(&kwin_conn, &name, context.kde5)?;
+        unregister_shortcut_key(&kwin_conn, &name);
+        forget_shortcut(&name)?;
+        return Ok(());
     }
 
-    let script_contents = generate_script(&context, parser, &next_arg.unwrap())?;
+    // The shortcut, screen edges and menu entry to register, if any.
+    let trigger = Shortcut {
+        name: String::new(),
+        shortcut: context.shortcut.clone(),
+        screen_edge: opt_screen_edge,
+        touch_edge: opt_touch_edge,
+        menu_entry: opt_menu_entry,
+        on_output: opt_on_output,
+        chain: vec![],
+        script_id: None,
+        installed: false,
+    };
+    set_triggers(&mut context, &trigger)?;
 
-    log::debug!("Script:{script_contents}");
-    script_file.write_all(script_contents.as_bytes())?;
-    let script_file_path = script_file.into_temp_path();
+    if opt_install && (!context.registered || context.script_name.is_empty()) {
+        return Err(anyhow!(
+            "--install requires --name, and --shortcut, a screen edge or a menu entry"
+        ));
+    }
 
-    if opt_dry_run {
-        println!("{}", script_contents.trim());
+    if !context.on_output.is_empty() && !context.registered {
+        return Err(anyhow!(
+            "--on-output requires --shortcut, a screen edge or a menu entry"
+        ));
+    }
+
+    if opt_replace && (!context.registered || context.script_name.is_empty()) {
+        return Err(anyhow!(
+            "--replace requires --name, and --shortcut, a screen edge or a menu entry"
+        ));
+    }
+
+    // Commands take --json after the command name.
+    if opt_json && !opt_list_shortcuts {
+        return Err(anyhow!("--json requires --list-shortcuts"));
+    }
+
+    if next_arg.as_deref() == Some("gc") {
+        for name in collect_garbage(&kwin_conn)? {
+            println!("Unloaded script: {name}");
+        }
         return Ok(());
     }
 
-    log::debug!("===== Load script into KWin =====");
-    let script_id: i32;
-    (script_id,) = kwin_proxy.method_call(
-        "org.kde.kwin.Scripting",
-        "loadScript",
-        (script_file_path.to_str().unwrap(), &context.script_name),
-    )?;
-    if script_id < 0 {
-        return Err(anyhow!("Failed to load script. A script with the same name may already exist. Please use `--remove` to remove it first."));
+    if next_arg.as_deref() == Some("shortcuts") {
+        return shortcuts_command(&kwin_conn, &context, parser, context.dry_run);
     }
 
-    log::debug!("Script ID: {script_id}");
-    log::debug!("Script name: {}", context.script_name);
+    if next_arg.as_deref() == Some("daemon") {
+        return run_daemon(&kwin_conn, context.kde5);
+    }
 
-    log::debug!("===== Run script =====");
-    let script_proxy = kwin_conn.with_proxy(
-        "org.kde.KWin",
-        if context.kde5 {
-            format!("/{script_id}")
-        } else {
-            format!("/Scripting/Script{script_id}")
-        },
-        Duration::from_millis(5000),
-    );
+    if next_arg.as_deref() == Some("serve") {
+        return serve(parser, &context);
+    }
 
-    // setup message receiver
-    let _receiver_thread = std::thread::spawn(move || {
-        let _receiver = self_conn.start_receive(
-            MatchRule::new_method_call(),
-            Box::new(|message, _connection| -> bool {
-                log::debug!("dbus message: {:?}", message);
-                if let Some(member) = message.member() {
-                    if let Some(arg) = message.get1() {
-                        let mut messages = MESSAGES.write().unwrap();
-                        messages.push((member.to_string(), arg));
+    if !context.kde5 && !context.registered && !context.physical_coords && !context.dry_run {
+        let first = next_arg.as_deref().unwrap();
+        if let Some(queries) = parse_window_queries(first, parser.raw_args()?.as_slice()) {
+            log::debug!("===== Query windows via getWindowInfo =====");
+            match query_windows(&kwin_conn, &queries) {
+                Ok(output) => {
+                    for line in output {
+                        println!("{line}");
                     }
+                    return Ok(());
                 }
-                true
-            }),
-        );
-        loop {
-            self_conn.process(Duration::from_millis(1000)).unwrap();
+                Err(err) => {
+                    log::debug!("getWindowInfo failed, falling back to a script: {err}");
+                }
+            }
         }
-        //FIXME: shut down this thread when the script is finished
-    });
+    }
 
-    let start_time = chrono::Local::now();
-    let _: () = script_proxy.method_call("org.kde.kwin.Script", "run", ())?;
-    if context.shortcut.is_empty() {
-        let _: () = script_proxy.method_call("org.kde.kwin.Script", "stop", ())?;
+    let self_conn = Arc::new(SyncConnection::new_session()?);
+    context.dbus_addr = self_conn.unique_name().to_string();
+    if !context.on_output.is_empty() {
+        context.dbus_addr = DAEMON_NAME.into();
     }
 
+    log::debug!("===== Generate KWin script =====");
+    let mut script_file = create_script_file(&mut context)?;
+
+    let chain: Vec<String> = std::iter::once(next_arg.clone().unwrap())
+        .chain(
+            parser
+                .raw_args()?
+                .as_slice()
+                .iter()
+                .map(|arg| arg.to_string_lossy().into()),
+        )
+        .collect();
+    let (script_contents, native_steps) = generate_script(&context, parser, &next_arg.unwrap())?;
+
+    log::debug!("Script:{script_contents}");
+    script_file.write_all(script_contents.as_bytes())?;
+    let script_file_path = script_file.into_temp_path();
+
+    if context.dry_run {
+        println!("{}", script_contents.trim());
+        return Ok(());
+    }
+
+    if context.registered {
+        if opt_replace {
+            remove_shortcut(&kwin_conn, &context.script_name)?;
+        } else if !opt_install && is_script_loaded(&kwin_conn, &context.script_name)? {
+            return Err(anyhow!(
+                "A script named '{}' is already loaded. Use --replace to replace it.",
+                context.script_name
+            ));
+        }
+        if !context.shortcut.is_empty() {
+            check_shortcut_key(&kwin_conn, &context.shortcut, &context.script_name)?;
+        }
+        if !context.on_output.is_empty() && !daemon_running(&kwin_conn) {
+            log::warn!("kdotool daemon isn't running. Until it's started, the output of the shortcut only goes to the KWin log.");
+        }
+    }
+
+    if opt_install {
+        let package_dir = install_script(
+            &kwin_conn,
+            &context.script_name,
+            &format!("kdotool {}", chain.join(" ")),
+            &script_contents,
+            context.kde5,
+        )?;
+        let entry = Shortcut {
+            name: context.script_name.clone(),
+            chain,
+            installed: true,
+            ..trigger
+        };
+        record_shortcut(entry.clone())?;
+        print_triggers(&entry, "installed");
+        println!("Script name: {}", context.script_name);
+        println!("Package: {}", package_dir.display());
+        return Ok(());
+    }
+
+    let start_time = chrono::Local::now();
+    let (script_id, messages) = run_script(
+        &kwin_conn,
+        self_conn,
+        &context,
+        &chain,
+        &script_file_path,
+        &native_steps,
+    )?;
+
     if context.debug {
         if let Ok(journal) = Command::new("journalctl")
             .arg(format!(
@@ -786,7 +1844,6 @@ fn main() -> anyhow::Result<()> {
     }
 
     log::debug!("===== Output =====");
-    let messages = MESSAGES.read().unwrap();
     for (msgtype, message) in messages.iter() {
         if msgtype == "result" {
             println!("{message}");
@@ -797,11 +1854,116 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
-    if !context.shortcut.is_empty() {
-        println!("Shortcut registered: {}", context.shortcut);
+    if context.registered {
+        // Shortcuts are never run by the daemon.
+        let script_id = script_id.unwrap();
+        let entry = Shortcut {
+            name: context.script_name.clone(),
+            chain,
+            script_id: Some(script_id),
+            ..trigger
+        };
+        record_shortcut(entry.clone())?;
+        print_triggers(&entry, "registered");
         println!("Script ID: {script_id}");
         println!("Script name: {}", context.script_name);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // Run a script against a mock of KWin's workspace with the given windows,
+    // and return the IDs of the windows it raised. Windows are given bottom to
+    // top, without their workspace.stackingOrder. None if node isn't installed.
+    fn raised_windows(script: &str, windows: serde_json::Value) -> Option<Vec<String>> {
+        const HARNESS: &str = r#"
+            let [script, windows] = process.argv.slice(1).map(JSON.parse);
+            let raised = [];
+            globalThis.print = () => {};
+            globalThis.callDBus = () => {};
+            globalThis.workspace = {
+                windowList: () => windows,
+                stackingOrder: windows,
+                raiseWindow: (w) => raised.push(w.internalId),
+            };
+            new Function(script)();
+            console.log(JSON.stringify(raised));
+        "#;
+        let output = match Command::new("node")
+            .args(["-e", HARNESS])
+            .arg(json!(script).to_string())
+            .arg(windows.to_string())
+            .output()
+        {
+            Ok(output) => output,
+            Err(err) => {
+                eprintln!("Skipping test, can't run node: {err}");
+                return None;
+            }
+        };
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Some(serde_json::from_slice(&output.stdout).unwrap())
+    }
+
+    #[test]
+    fn windowlower() {
+        let window = |id: &str, normal: bool, desktops: &[i32], activities: &[&str]| {
+            json!({
+                "internalId": id,
+                "caption": id,
+                "resourceClass": id,
+                "resourceName": id,
+                "windowRole": "",
+                "normalWindow": normal,
+                "onAllDesktops": desktops.is_empty(),
+                "desktops": desktops,
+                "activities": activities,
+            })
+        };
+        let windows = json!([
+            window("desktop", false, &[], &[]),
+            window("below", true, &[1], &[]),
+            window("lowered", true, &[1], &["a"]),
+            window("other-desktop", true, &[2], &["a"]),
+            window("other-activity", true, &[1], &["b"]),
+            window("panel", false, &[], &[]),
+            window("sticky", true, &[], &["a", "b"]),
+        ]);
+        let (script, _) = generate_script(
+            &Globals::default(),
+            Parser::from_args(["--class", "^lowered$", "windowlower"]),
+            "search",
+        )
+        .unwrap();
+        if let Some(raised) = raised_windows(&script, windows) {
+            assert_eq!(raised, ["below", "sticky"]);
+        }
+    }
+
+    #[test]
+    fn screen_edges() {
+        assert_eq!(screen_edge_expr("top", false).unwrap(), "KWin.ElectricTop");
+        assert_eq!(screen_edge_expr("top", true).unwrap(), "KWin.ElectricTop");
+        assert_eq!(
+            screen_edge_expr("bottom-left", false).unwrap(),
+            "KWin.ElectricBottomLeft"
+        );
+        // Touch edges are only the sides of the screen.
+        let err = screen_edge_expr("top-left", true).unwrap_err();
+        assert_eq!(err.to_string(), "invalid touch edge 'top-left'");
+        let err = screen_edge_expr("bogus", false).unwrap_err();
+        assert_eq!(err.to_string(), "invalid screen edge 'bogus'");
+        assert!(screen_edge_expr("Top", false).is_err());
+        assert!(screen_edge_expr("", true).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+// Steps that are executed by kdotool itself, instead of the KWin script.
+//
+// The script calls back into kdotool when it reaches such a step, and continues
+// with the rest of the chain when kdotool replies. The reply is passed to the
+// script as `native_result`.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use dbus::{
+    arg::PropMap,
+    blocking::{stdintf::org_freedesktop_dbus::Properties, Connection, Proxy},
+};
+use serde_json::json;
+
+use crate::activities::*;
+
+const VIRTUAL_DESKTOP_MANAGER: &str = "org.kde.KWin.VirtualDesktopManager";
+
+const DBUS_TIMEOUT: Duration = Duration::from_millis(5000);
+// Give the user some time to click. The script's call to kdotool times out
+// after 25 seconds.
+const SELECT_WINDOW_TIMEOUT: Duration = Duration::from_millis(20000);
+
+pub enum NativeStep {
+    CreateDesktop { position: Option<u32>, name: String },
+    RemoveDesktop { desktop: String },
+    RenameDesktop { desktop: String, name: String },
+    SetDesktopRows { rows: u32 },
+    GetActivity,
+    SetActivity { activity: String },
+    ListActivities,
+    CreateActivity { name: String },
+    StopActivity { activity: String },
+    SelectWindow,
+}
+
+impl NativeStep {
+    // How long the step may block, waiting for D-Bus calls or the user.
+    pub fn timeout(&self) -> Duration {
+        match self {
+            NativeStep::SelectWindow => SELECT_WINDOW_TIMEOUT,
+            _ => DBUS_TIMEOUT,
+        }
+    }
+
+    pub fn run(&self, conn: &Connection) -> anyhow::Result<serde_json::Value> {
+        self.run_with(conn, &activity_manager(conn))
+    }
+
+    // Run the step, with activities managed by the given ActivityManager.
+    pub fn run_with(
+        &self,
+        conn: &Connection,
+        activities: &Proxy<&Connection>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let proxy = conn.with_proxy("org.kde.KWin", "/VirtualDesktopManager", self.timeout());
+
+        match self {
+            NativeStep::CreateDesktop { position, name } => {
+                let position = match position {
+                    Some(n) => n - 1,
+                    None => proxy.get(VIRTUAL_DESKTOP_MANAGER, "count")?,
+                };
+                let _: () = proxy.method_call(
+                    VIRTUAL_DESKTOP_MANAGER,
+                    "createDesktop",
+                    (position, name),
+                )?;
+            }
+
+            NativeStep::RemoveDesktop { desktop } => {
+                let id = desktop_uuid(&proxy, desktop)?;
+                let _: () = proxy.method_call(VIRTUAL_DESKTOP_MANAGER, "removeDesktop", (id,))?;
+            }
+
+            NativeStep::RenameDesktop { desktop, name } => {
+                let id = desktop_uuid(&proxy, desktop)?;
+                let _: () =
+                    proxy.method_call(VIRTUAL_DESKTOP_MANAGER, "setDesktopName", (id, name))?;
+            }
+
+            NativeStep::SetDesktopRows { rows } => {
+                proxy.set(VIRTUAL_DESKTOP_MANAGER, "rows", *rows)?;
+            }
+
+            NativeStep::GetActivity => {
+                let id = current_activity(activities)?;
+                let name = activity_name(activities, &id)?;
+                return Ok(json!({ "id": id, "name": name }));
+            }
+
+            NativeStep::SetActivity { activity } => {
+                set_current_activity(activities, &activity_id(activities, activity)?)?;
+            }
+
+            NativeStep::ListActivities => {
+                let current = current_activity(activities)?;
+                let activities: Vec<serde_json::Value> = list_activities_with_state(activities)?
+                    .into_iter()
+                    .map(|(id, name, state)| {
+                        json!({ "id": id, "name": name, "state": state, "current": id == current })
+                    })
+                    .collect();
+                return Ok(activities.into());
+            }
+
+            NativeStep::CreateActivity { name } => {
+                return Ok(add_activity(activities, name)?.into());
+            }
+
+            NativeStep::StopActivity { activity } => {
+                stop_activity(activities, &activity_id(activities, activity)?)?;
+            }
+
+            NativeStep::SelectWindow => {
+                let proxy = conn.with_proxy("org.kde.KWin", "/KWin", self.timeout());
+                let (info,): (PropMap,) =
+                    proxy.method_call("org.kde.KWin", "queryWindowInfo", ())?;
+                let uuid = info
+                    .get("uuid")
+                    .and_then(|v| v.0.as_str())
+                    .ok_or(anyhow!("No window selected"))?;
+                return Ok(uuid.into());
+            }
+        }
+
+        Ok(serde_json::Value::Null)
+    }
+}
+
+// Map a desktop number (starting from 1), "name:NAME" or UUID to the desktop's
+// UUID. This matches find_desktop() in the script.
+fn desktop_uuid(proxy: &Proxy<&Connection>, desktop: &str) -> anyhow::Result<String> {
+    let desktops: Vec<(u32, String, String)> = proxy.get(VIRTUAL_DESKTOP_MANAGER, "desktops")?;
+    desktops
+        .into_iter()
+        .find(|(position, id, name)| {
+            if let Some(s) = desktop.strip_prefix("name:") {
+                name == s
+            } else if let Ok(n) = desktop.parse::<u32>() {
+                position + 1 == n
+            } else {
+                id == desktop
+            }
+        })
+        .map(|(_, id, _)| id)
+        .ok_or(anyhow!("Invalid desktop '{desktop}'"))
+}
@@ -0,0 +1,178 @@
+// Output of shortcut scripts. When a shortcut is triggered, the kdotool process
+// which registered it is long gone, so with `--on-output` the script sends its
+// output to `kdotool daemon` instead, which passes it on:
+//
+//   notify      as a desktop notification
+//   log:FILE    appended to a file
+//   exec:CMD    to a shell command, as $1
+//
+// Results are printed to the KWin log as well, in case the daemon isn't
+// running.
+//
+// Any client on the bus can call the daemon, so it only takes the routes which
+// are recorded for the scripts in the shortcut registry.
+
+use std::io::Write;
+use std::process::Command;
+
+use anyhow::anyhow;
+use dbus::{arg::PropMap, blocking::Connection, channel::Sender, Message};
+
+use crate::shortcuts::{load_shortcuts, Shortcut};
+
+// Check a route, and make the file of a log route absolute, as the daemon runs
+// in another directory.
+pub fn parse_output_route(route: &str) -> anyhow::Result<String> {
+    if route == "notify" {
+        Ok(route.into())
+    } else if let Some(file) = route.strip_prefix("log:").filter(|f| !f.is_empty()) {
+        Ok(format!("log:{}", std::path::absolute(file)?.display()))
+    } else if route.strip_prefix("exec:").is_some_and(|c| !c.is_empty()) {
+        Ok(route.into())
+    } else {
+        Err(anyhow!("invalid output route '{route}'"))
+    }
+}
+
+// The route recorded for a shortcut script, if it's the one the script sent.
+fn registered_route(
+    shortcuts: &[Shortcut],
+    script_name: &str,
+    route: &str,
+) -> anyhow::Result<String> {
+    shortcuts
+        .iter()
+        .find(|s| s.name == script_name)
+        .and_then(|s| s.on_output.clone())
+        .filter(|registered| registered == route)
+        .ok_or(anyhow!(
+            "'{route}' isn't the output route registered for {script_name}"
+        ))
+}
+
+// Pass on a message of a shortcut script, along the route registered for it.
+pub fn route_registered_output(
+    conn: &Connection,
+    route: &str,
+    script_name: &str,
+    msgtype: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let route = registered_route(&load_shortcuts()?, script_name, route)?;
+    route_output(conn, &route, script_name, msgtype, message)
+}
+
+// Pass on a message of a shortcut script. msgtype is "result" or "error".
+fn route_output(
+    conn: &Connection,
+    route: &str,
+    script_name: &str,
+    msgtype: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let is_error = msgtype == "error";
+    if route == "notify" {
+        let notify = Message::new_method_call(
+            "org.freedesktop.Notifications",
+            "/org/freedesktop/Notifications",
+            "org.freedesktop.Notifications",
+            "Notify",
+        )
+        .map_err(|err| anyhow!(err))?
+        .append3("kdotool", 0u32, if is_error { "dialog-error" } else { "" })
+        .append3(
+            if is_error {
+                format!("kdotool error: {script_name}")
+            } else {
+                format!("kdotool: {script_name}")
+            },
+            message,
+            Vec::<String>::new(),
+        )
+        .append2(PropMap::new(), -1i32);
+        conn.send(notify)
+            .map_err(|_| anyhow!("Failed to send notification"))?;
+    } else if let Some(file) = route.strip_prefix("log:") {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)?;
+        writeln!(
+            file,
+            "{} [{script_name}] {}{message}",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+            if is_error { "ERROR: " } else { "" }
+        )?;
+    } else if let Some(command) = route.strip_prefix("exec:") {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .arg("kdotool")
+            .arg(message)
+            .env("KDOTOOL_OUTPUT_TYPE", msgtype)
+            .env("KDOTOOL_SCRIPT_NAME", script_name)
+            .spawn()?;
+        std::thread::spawn(move || child.wait());
+    } else {
+        return Err(anyhow!("invalid output route '{route}'"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_routes() {
+        assert_eq!(parse_output_route("notify").unwrap(), "notify");
+        assert_eq!(
+            parse_output_route("log:/tmp/out.log").unwrap(),
+            "log:/tmp/out.log"
+        );
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            parse_output_route("log:out.log").unwrap(),
+            format!("log:{}", cwd.join("out.log").display())
+        );
+        assert_eq!(
+            parse_output_route("exec:logger -t \"$KDOTOOL_SCRIPT_NAME\"").unwrap(),
+            "exec:logger -t \"$KDOTOOL_SCRIPT_NAME\""
+        );
+        for route in ["", "log:", "exec:", "notify:", "Notify", "foo"] {
+            assert!(parse_output_route(route).is_err(), "{route}");
+        }
+    }
+
+    #[test]
+    fn registered_routes() {
+        let shortcut = |name: &str, on_output: Option<&str>| Shortcut {
+            name: name.into(),
+            shortcut: "Meta+F1".into(),
+            screen_edge: None,
+            touch_edge: None,
+            menu_entry: None,
+            on_output: on_output.map(String::from),
+            chain: vec!["getactivewindow".into()],
+            script_id: None,
+            installed: false,
+        };
+        let shortcuts = [
+            shortcut("logged", Some("log:/tmp/out.log")),
+            shortcut("run", Some("exec:notify-send \"$1\"")),
+            shortcut("quiet", None),
+        ];
+        assert_eq!(
+            registered_route(&shortcuts, "logged", "log:/tmp/out.log").unwrap(),
+            "log:/tmp/out.log"
+        );
+        assert_eq!(
+            registered_route(&shortcuts, "run", "exec:notify-send \"$1\"").unwrap(),
+            "exec:notify-send \"$1\""
+        );
+        assert!(registered_route(&shortcuts, "run", "exec:rm -rf ~").is_err());
+        assert!(registered_route(&shortcuts, "logged", "log:/tmp/other.log").is_err());
+        assert!(registered_route(&shortcuts, "quiet", "notify").is_err());
+        assert!(registered_route(&shortcuts, "unknown", "exec:true").is_err());
+    }
+}
@@ -39,10 +39,46 @@ where
     }
 }
 
+// KWin's window IDs are lower-case.
 pub fn to_window_id(s: &str) -> Option<String> {
-    if s.starts_with('%') || s.starts_with('{') {
+    if s.starts_with('{') {
+        Some(s.to_lowercase())
+    } else if s.starts_with('%') {
         Some(s.into())
     } else {
         None
     }
 }
+
+pub fn is_coordinate(s: &str) -> bool {
+    s == "x" || s == "y" || s.strip_suffix('%').unwrap_or(s).parse::<i32>().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coordinates() {
+        assert!(is_coordinate("x"));
+        assert!(is_coordinate("y"));
+        assert!(is_coordinate("100"));
+        assert!(is_coordinate("-20"));
+        assert!(is_coordinate("50%"));
+        assert!(!is_coordinate("next"));
+        assert!(!is_coordinate("HDMI-1"));
+        assert!(!is_coordinate("%1"));
+        assert!(!is_coordinate(""));
+    }
+
+    #[test]
+    fn window_ids() {
+        assert_eq!(to_window_id("%1").as_deref(), Some("%1"));
+        assert_eq!(to_window_id("%@").as_deref(), Some("%@"));
+        assert_eq!(
+            to_window_id("{8A6B8B3F-7BD5-4C1E-9D46-3C5A1A2B9E10}").as_deref(),
+            Some("{8a6b8b3f-7bd5-4c1e-9d46-3c5a1a2b9e10}")
+        );
+        assert_eq!(to_window_id("100"), None);
+    }
+}
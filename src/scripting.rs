@@ -0,0 +1,304 @@
+// Loading scripts via KWin's org.kde.kwin.Scripting DBus interface.
+//
+// Scripts that shouldn't outlive kdotool are recorded in a file each, under
+// $XDG_RUNTIME_DIR/kdotool/scripts, with the PID of the kdotool process, the
+// script file and the start time of the process. `kdotool gc` unloads the
+// scripts of processes that have died without cleaning up.
+//
+// kdotool processes which crashed, or were too old to record their scripts,
+// leave their kdotool-* script files behind in the temporary directory, and
+// the scripts are named after the files. `kdotool gc` unloads those, too.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use dbus::blocking::{Connection, Proxy};
+
+const SCRIPTING: &str = "org.kde.kwin.Scripting";
+
+// The temporary scripts loaded by this process, and their script files.
+static TEMPORARY_SCRIPTS: Mutex<Vec<(String, PathBuf)>> = Mutex::new(Vec::new());
+
+fn scripting_proxy(conn: &Connection) -> Proxy<'_, &Connection> {
+    conn.with_proxy("org.kde.KWin", "/Scripting", Duration::from_millis(5000))
+}
+
+// Load a script into KWin, and return its ID and a proxy for running and
+// stopping it.
+pub fn load_script<'a>(
+    conn: &'a Connection,
+    path: &Path,
+    name: &str,
+    kde5: bool,
+) -> anyhow::Result<(i32, Proxy<'a, &'a Connection>)> {
+    let (script_id,): (i32,) = scripting_proxy(conn).method_call(
+        SCRIPTING,
+        "loadScript",
+        (path.to_str().unwrap(), name),
+    )?;
+    if script_id < 0 {
+        return Err(anyhow!("Failed to load script. A script with the same name may already exist. Please use `--replace` to replace it, or `--remove` to remove it first."));
+    }
+    let script_proxy = conn.with_proxy(
+        "org.kde.KWin",
+        if kde5 {
+            format!("/{script_id}")
+        } else {
+            format!("/Scripting/Script{script_id}")
+        },
+        Duration::from_millis(5000),
+    );
+    Ok((script_id, script_proxy))
+}
+
+// Load a script which is unloaded when kdotool exits, or else by `kdotool gc`.
+pub fn load_temporary_script<'a>(
+    conn: &'a Connection,
+    path: &Path,
+    name: &str,
+    kde5: bool,
+) -> anyhow::Result<(i32, Proxy<'a, &'a Connection>)> {
+    let result = load_script(conn, path, name, kde5)?;
+    let dir = registry_dir();
+    std::fs::create_dir_all(&dir)?;
+    let pid = std::process::id().to_string();
+    std::fs::write(
+        dir.join(name),
+        format!(
+            "{pid}\n{}\n{}\n",
+            path.display(),
+            process_start_time(&pid).unwrap_or_default()
+        ),
+    )?;
+    TEMPORARY_SCRIPTS
+        .lock()
+        .unwrap()
+        .push((name.into(), path.into()));
+    Ok(result)
+}
+
+fn registry_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("kdotool")
+        .join("scripts")
+}
+
+pub fn is_script_loaded(conn: &Connection, name: &str) -> anyhow::Result<bool> {
+    let (loaded,): (bool,) =
+        scripting_proxy(conn).method_call(SCRIPTING, "isScriptLoaded", (name,))?;
+    Ok(loaded)
+}
+
+pub fn unload_script(conn: &Connection, name: &str) -> anyhow::Result<bool> {
+    let (unloaded,): (bool,) =
+        scripting_proxy(conn).method_call(SCRIPTING, "unloadScript", (name,))?;
+    TEMPORARY_SCRIPTS
+        .lock()
+        .unwrap()
+        .retain(|(script, _)| script != name);
+    _ = std::fs::remove_file(registry_dir().join(name));
+    Ok(unloaded)
+}
+
+// Unload the temporary scripts of this process, e.g. when it's interrupted.
+// The script files are removed too, as their destructors won't run.
+pub fn unload_temporary_scripts(conn: &Connection) {
+    let scripts = std::mem::take(&mut *TEMPORARY_SCRIPTS.lock().unwrap());
+    for (name, path) in scripts {
+        if let Err(err) = unload_script(conn, &name) {
+            log::warn!("Failed to unload {name}: {err}");
+        }
+        _ = std::fs::remove_file(path);
+    }
+}
+
+// The start time of a process from /proc/PID/stat, in clock ticks since boot.
+// Together with the PID, it identifies the process, even if the PID is reused.
+fn process_start_time(pid: &str) -> Option<u64> {
+    let stat = std::fs::read_to_string(Path::new("/proc").join(pid).join("stat")).ok()?;
+    parse_start_time(&stat)
+}
+
+fn parse_start_time(stat: &str) -> Option<u64> {
+    // The command name in parentheses may contain spaces and parentheses. The
+    // start time is the 22nd field, and the 20th after the command name.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+// Whether the kdotool process which recorded a script is still running.
+fn is_process_alive(pid: &str, start_time: Option<&str>) -> bool {
+    if pid.is_empty() {
+        return false;
+    }
+    match start_time.filter(|t| !t.is_empty()) {
+        Some(start_time) => process_start_time(pid).is_some_and(|t| t.to_string() == start_time),
+        // Recorded without a start time, so check that it's still kdotool.
+        None => std::fs::read_link(Path::new("/proc").join(pid).join("exe"))
+            .is_ok_and(|exe| exe.file_name().is_some_and(|name| name == "kdotool")),
+    }
+}
+
+// Script files which are younger than this may belong to a kdotool process
+// which is still running, but doesn't record its scripts.
+const LEAKED_SCRIPT_AGE: Duration = Duration::from_secs(60);
+
+// Script files are named "kdotool-" and the random suffix of their tempfile.
+const SCRIPT_FILE_PREFIX: &str = "kdotool-";
+const SCRIPT_FILE_SUFFIX_LEN: usize = 6;
+
+fn is_script_file_name(name: &str) -> bool {
+    name.strip_prefix(SCRIPT_FILE_PREFIX).is_some_and(|suffix| {
+        suffix.len() == SCRIPT_FILE_SUFFIX_LEN && suffix.chars().all(|c| c.is_ascii_alphanumeric())
+    })
+}
+
+// Whether path may be a script file of ours: a regular file with the name of
+// one, which belongs to the current user.
+fn is_script_file(path: &Path) -> bool {
+    // /proc/self belongs to the user the process runs as.
+    let uid = std::fs::metadata("/proc/self").map(|m| m.uid());
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(is_script_file_name)
+        && std::fs::symlink_metadata(path)
+            .is_ok_and(|m| m.file_type().is_file() && uid.as_ref().is_ok_and(|uid| *uid == m.uid()))
+}
+
+// Unload a script of a kdotool process which is gone, if it's loaded. Errors
+// are logged, so that the other scripts are still collected, and None is
+// returned.
+fn unload_leaked_script(conn: &Connection, name: &str, unloaded: &mut Vec<String>) -> Option<bool> {
+    let result = is_script_loaded(conn, name).and_then(|loaded| {
+        if loaded {
+            unload_script(conn, name)?;
+        }
+        Ok(loaded)
+    });
+    match result {
+        Ok(loaded) => {
+            if loaded {
+                unloaded.push(name.into());
+            }
+            Some(loaded)
+        }
+        Err(err) => {
+            log::warn!("Failed to unload {name}: {err}");
+            None
+        }
+    }
+}
+
+// Unload the temporary scripts of kdotool processes which are gone, and return
+// their names.
+pub fn collect_garbage(conn: &Connection) -> anyhow::Result<Vec<String>> {
+    let mut unloaded = vec![];
+    // Names and files of the recorded scripts.
+    let mut recorded = vec![];
+    if let Ok(entries) = std::fs::read_dir(registry_dir()) {
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    log::warn!("Failed to read {}: {err}", registry_dir().display());
+                    continue;
+                }
+            };
+            let name = entry.file_name().to_string_lossy().to_string();
+            let contents = match std::fs::read_to_string(entry.path()) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    log::warn!("Failed to read {}: {err}", entry.path().display());
+                    continue;
+                }
+            };
+            let mut lines = contents.lines();
+            let pid = lines.next().unwrap_or_default();
+            let path = lines.next().unwrap_or_default();
+            if is_process_alive(pid, lines.next()) {
+                recorded.push(name);
+                recorded.push(path.to_string());
+                continue;
+            }
+            // Keep the record to try again later.
+            if unload_leaked_script(conn, &name, &mut unloaded).is_none() {
+                continue;
+            }
+            if is_script_file(Path::new(path)) {
+                _ = std::fs::remove_file(path);
+            }
+            _ = std::fs::remove_file(entry.path());
+        }
+    }
+
+    // Shortcut scripts are named after their script file by default, but
+    // their files are removed when they have been loaded.
+    let shortcuts: Vec<String> = crate::shortcuts::load_shortcuts()?
+        .into_iter()
+        .map(|s| s.name)
+        .collect();
+    for entry in std::fs::read_dir(std::env::temp_dir())? {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = entry.path().to_string_lossy().to_string();
+        let leaked = is_script_file(&entry.path())
+            && !recorded.contains(&name)
+            && !recorded.contains(&path)
+            && !shortcuts.contains(&name)
+            && entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|t| t.elapsed().is_ok_and(|age| age > LEAKED_SCRIPT_AGE));
+        // Other files may have the same name, so only the files of scripts
+        // which are loaded are removed.
+        if leaked && unload_leaked_script(conn, &name, &mut unloaded) == Some(true) {
+            _ = std::fs::remove_file(entry.path());
+        }
+    }
+    Ok(unloaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_times() {
+        let stat = "1234 (kdo tool) (x)) S 1 1234 1234 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 987654 12345 67";
+        assert_eq!(parse_start_time(stat), Some(987654));
+        assert_eq!(parse_start_time("1234 (kdotool) S 1"), None);
+
+        let pid = std::process::id().to_string();
+        let start_time = process_start_time(&pid).unwrap().to_string();
+        assert!(is_process_alive(&pid, Some(&start_time)));
+        assert!(!is_process_alive(&pid, Some("1")));
+        assert!(!is_process_alive("", None));
+    }
+
+    #[test]
+    fn script_files() {
+        assert!(is_script_file_name("kdotool-a1B2c3"));
+        assert!(!is_script_file_name("kdotool-daemon-1"));
+        assert!(!is_script_file_name("kdotool-abc"));
+        assert!(!is_script_file_name("kdotool-abcdef.js"));
+        assert!(!is_script_file_name("kdotool-abc.de"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = tempfile::NamedTempFile::with_prefix_in(SCRIPT_FILE_PREFIX, dir.path()).unwrap();
+        assert!(is_script_file(file.path()));
+        let other = dir.path().join("kdotool-other");
+        assert!(!is_script_file(&other));
+        std::os::unix::fs::symlink(file.path(), &other).unwrap();
+        assert!(!is_script_file(&other));
+        std::fs::remove_file(&other).unwrap();
+        std::fs::create_dir(&other).unwrap();
+        assert!(!is_script_file(&other));
+    }
+}
@@ -0,0 +1,515 @@
+// `kdotool serve --socket PATH` exposes the commands as a JSON-RPC 2.0 API over
+// a Unix socket, one JSON object per line. The method is the command name, and
+// the params are its arguments as on the command line, e.g.
+//
+//   {"jsonrpc": "2.0", "id": 1, "method": "windowmove", "params": ["%1", "100", "200"]}
+//
+// The result is an array of output lines. Every connection has its own window
+// stack, which is kept from one request to the next.
+//
+// The "subscribe" method loads a script which reports window events, which are
+// sent to the client as "event" notifications, until "unsubscribe" is called or
+// the connection is closed.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use dbus::{
+    blocking::{Connection, SyncConnection},
+    channel::{BusType, Channel, MatchingReceiver},
+    message::MatchRule,
+};
+use serde_json::{json, Value};
+
+use crate::scripting::*;
+use crate::templates::{SCRIPT_FOOTER, SCRIPT_HEADER, STEP_SUBSCRIBE};
+use crate::{create_script_file, generate_script, run_script, Globals, Parser};
+
+const EVENTS: &[&str] = &[
+    "window_added",
+    "window_removed",
+    "window_activated",
+    "desktop_changed",
+    "activity_changed",
+];
+
+// JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const INVALID_PARAMS: i64 = -32602;
+const COMMAND_FAILED: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+struct Subscription {
+    script_name: String,
+    events: Arc<Mutex<Vec<String>>>,
+    done: Arc<AtomicBool>,
+    _script_file: tempfile::TempPath,
+}
+
+struct Session {
+    kwin_conn: Connection,
+    // The bus the scripts report on, or None for the session bus.
+    bus_address: Option<String>,
+    globals: Globals,
+    writer: Arc<Mutex<UnixStream>>,
+    window_stack: Vec<String>,
+    subscription: Option<Subscription>,
+}
+
+fn send_line(writer: &Mutex<UnixStream>, value: &Value) -> std::io::Result<()> {
+    let mut writer = writer.lock().unwrap();
+    writeln!(writer, "{value}")?;
+    writer.flush()
+}
+
+impl Session {
+    fn connect(&self) -> Result<Channel, dbus::Error> {
+        match &self.bus_address {
+            Some(address) => {
+                let mut channel = Channel::open_private(address)?;
+                channel.register()?;
+                Ok(channel)
+            }
+            None => Channel::get_private(BusType::Session),
+        }
+    }
+
+    fn handle_line(&mut self, line: &str) -> Option<Value> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(err) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, err))),
+        };
+        let id = request.get("id").cloned();
+        let method = request.get("method").and_then(Value::as_str);
+        let result = match method {
+            Some(method) if request.get("jsonrpc") == Some(&json!("2.0")) => {
+                self.handle_request(method, request.get("params"))
+            }
+            _ => Err(RpcError::new(INVALID_REQUEST, "Invalid request")),
+        };
+        // Requests without an ID are notifications, which get no response.
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(err) => error_response(id, err),
+        })
+    }
+
+    fn handle_request(&mut self, method: &str, params: Option<&Value>) -> Result<Value, RpcError> {
+        let args: Vec<String> = match params {
+            None => vec![],
+            Some(params) => serde_json::from_value(params.clone())
+                .map_err(|_| RpcError::new(INVALID_PARAMS, "params must be an array of strings"))?,
+        };
+        match method {
+            "subscribe" => self.subscribe(args).map(|()| json!([])),
+            "unsubscribe" => {
+                self.unsubscribe();
+                Ok(json!([]))
+            }
+            _ => self.run_command(method, args).map(Value::from),
+        }
+    }
+
+    fn run_command(&mut self, command: &str, args: Vec<String>) -> Result<Vec<String>, RpcError> {
+        let failed = |err: anyhow::Error| RpcError::new(COMMAND_FAILED, format!("{err:#}"));
+
+        let self_conn = Arc::new(SyncConnection::from(
+            self.connect().map_err(|e| failed(e.into()))?,
+        ));
+        let mut context = self.globals.clone();
+        context.dbus_addr = self_conn.unique_name().to_string();
+        context.window_stack = Some(serde_json::to_string(&self.window_stack).unwrap());
+        let mut script_file = create_script_file(&mut context).map_err(failed)?;
+
+        let chain: Vec<String> = std::iter::once(command.to_string())
+            .chain(args.iter().cloned())
+            .collect();
+        let (script_contents, native_steps) =
+            generate_script(&context, Parser::from_args(args), command)
+                .map_err(|err| RpcError::new(INVALID_PARAMS, format!("{err:#}")))?;
+        log::debug!("Script:{script_contents}");
+        script_file
+            .write_all(script_contents.as_bytes())
+            .map_err(|e| failed(e.into()))?;
+        let script_file_path = script_file.into_temp_path();
+
+        let (_, messages) = run_script(
+            &self.kwin_conn,
+            self_conn,
+            &context,
+            &chain,
+            &script_file_path,
+            &native_steps,
+        )
+        .map_err(failed)?;
+
+        let mut output = vec![];
+        let mut errors = vec![];
+        for (msgtype, message) in messages {
+            match msgtype.as_str() {
+                "result" => output.push(message),
+                "error" => errors.push(message),
+                "window_stack" => {
+                    if let Ok(window_stack) = serde_json::from_str(&message) {
+                        self.window_stack = window_stack;
+                    }
+                }
+                _ => output.push(format!("{msgtype}: {message}")),
+            }
+        }
+        if errors.is_empty() {
+            Ok(output)
+        } else {
+            Err(RpcError {
+                data: Some(json!({ "output": output })),
+                ..RpcError::new(COMMAND_FAILED, errors.join("\n"))
+            })
+        }
+    }
+
+    fn subscribe(&mut self, events: Vec<String>) -> Result<(), RpcError> {
+        if let Some(event) = events.iter().find(|e| !EVENTS.contains(&e.as_str())) {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("unknown event '{event}'"),
+            ));
+        }
+        // Subscribing again only changes the events that are sent.
+        if let Some(subscription) = &self.subscription {
+            *subscription.events.lock().unwrap() = events;
+            return Ok(());
+        }
+        self.subscription = Some(
+            self.load_events_script(events)
+                .map_err(|err| RpcError::new(COMMAND_FAILED, format!("{err:#}")))?,
+        );
+        Ok(())
+    }
+
+    fn load_events_script(&self, events: Vec<String>) -> anyhow::Result<Subscription> {
+        let events_conn = Connection::from(self.connect()?);
+        let mut context = self.globals.clone();
+        context.dbus_addr = events_conn.unique_name().to_string();
+        let mut script_file = create_script_file(&mut context)?;
+
+        let mut reg = handlebars::Handlebars::new();
+        reg.set_strict_mode(true);
+        let render_context = handlebars::Context::wraps(&context)?;
+        let mut script = String::new();
+        for template in [SCRIPT_HEADER, STEP_SUBSCRIBE, SCRIPT_FOOTER] {
+            script.push_str(&reg.render_template_with_context(template, &render_context)?);
+        }
+        log::debug!("Script:{script}");
+        script_file.write_all(script.as_bytes())?;
+        let script_file_path = script_file.into_temp_path();
+
+        // Forward the events to the client.
+        let events = Arc::new(Mutex::new(events));
+        let receiver_events = events.clone();
+        let writer = self.writer.clone();
+        events_conn.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, _connection| -> bool {
+                let Some(arg) = message.get1::<String>() else {
+                    return true;
+                };
+                let params = match message.member().as_deref() {
+                    Some("event") => match serde_json::from_str::<Value>(&arg) {
+                        Ok(params) => params,
+                        Err(_) => return true,
+                    },
+                    Some("error") => json!({ "event": "error", "message": arg }),
+                    _ => return true,
+                };
+                let event = params["event"].as_str().unwrap_or_default();
+                let events = receiver_events.lock().unwrap();
+                if event == "error" || events.is_empty() || events.iter().any(|e| e == event) {
+                    _ = send_line(
+                        &writer,
+                        &json!({ "jsonrpc": "2.0", "method": "event", "params": params }),
+                    );
+                }
+                true
+            }),
+        );
+        let done = Arc::new(AtomicBool::new(false));
+        let receiver_done = done.clone();
+        std::thread::spawn(move || {
+            while !receiver_done.load(Ordering::Relaxed) {
+                if events_conn.process(Duration::from_millis(1000)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let (_, script_proxy) = load_temporary_script(
+            &self.kwin_conn,
+            &script_file_path,
+            &context.script_name,
+            context.kde5,
+        )?;
+        let _: () = script_proxy.method_call("org.kde.kwin.Script", "run", ())?;
+
+        Ok(Subscription {
+            script_name: context.script_name,
+            events,
+            done,
+            _script_file: script_file_path,
+        })
+    }
+
+    fn unsubscribe(&mut self) {
+        if let Some(subscription) = self.subscription.take() {
+            if let Err(err) = unload_script(&self.kwin_conn, &subscription.script_name) {
+                log::warn!("Failed to unload {}: {err}", subscription.script_name);
+            }
+            subscription.done.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+fn error_response(id: Value, err: RpcError) -> Value {
+    let mut error = json!({ "code": err.code, "message": err.message });
+    if let Some(data) = err.data {
+        error["data"] = data;
+    }
+    json!({ "jsonrpc": "2.0", "id": id, "error": error })
+}
+
+fn serve_connection(stream: UnixStream, globals: Globals) -> anyhow::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut session = Session {
+        kwin_conn: Connection::new_session()?,
+        bus_address: None,
+        globals,
+        writer: Arc::new(Mutex::new(stream)),
+        window_stack: vec![],
+        subscription: None,
+    };
+    let result = (|| {
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = session.handle_line(&line) {
+                send_line(&session.writer, &response)?;
+            }
+        }
+        Ok(())
+    })();
+    session.unsubscribe();
+    result
+}
+
+fn bind(path: &PathBuf) -> anyhow::Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{} exists and isn't a socket", path.display()));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(anyhow!("{} is already in use", path.display()));
+        }
+        // Left behind by a previous server.
+        std::fs::remove_file(path)?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+pub fn serve(mut parser: Parser, globals: &Globals) -> anyhow::Result<()> {
+    let mut socket_path = None;
+    while let Some(arg) = parser.next()? {
+        use lexopt::prelude::*;
+        match arg {
+            Long("socket") => {
+                socket_path = Some(PathBuf::from(parser.value()?));
+            }
+            _ => {
+                return Err(arg.unexpected().into());
+            }
+        }
+    }
+    let socket_path = socket_path.ok_or(anyhow!("missing option --socket"))?;
+    if globals.registered {
+        return Err(anyhow!("--shortcut can't be used with serve"));
+    }
+    // Every script needs its own name, as they may run at the same time.
+    let globals = Globals {
+        script_name: String::new(),
+        ..globals.clone()
+    };
+
+    let listener = bind(&socket_path)?;
+    log::info!("kdotool is serving on {}", socket_path.display());
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let globals = globals.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = serve_connection(stream, globals) {
+                log::warn!("Connection closed: {err}");
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use dbus::{channel::Sender, Message};
+
+    use super::*;
+    use crate::test_bus::TestBus;
+
+    fn session(bus: &TestBus) -> (Session, UnixStream) {
+        let (writer, client) = UnixStream::pair().unwrap();
+        let session = Session {
+            kwin_conn: bus.connect(),
+            bus_address: Some(bus.address.clone()),
+            globals: Globals::default(),
+            writer: Arc::new(Mutex::new(writer)),
+            window_stack: vec![],
+            subscription: None,
+        };
+        (session, client)
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        let (mut session, _client) = session(&bus);
+        let error = |response: Option<Value>| {
+            let response = response.unwrap();
+            (response["id"].clone(), response["error"]["code"].clone())
+        };
+        assert_eq!(
+            error(session.handle_line("{\"jsonrpc\": \"2.0\",")),
+            (Value::Null, json!(PARSE_ERROR))
+        );
+        assert_eq!(
+            error(
+                session.handle_line(r#"{"jsonrpc": "1.0", "id": 1, "method": "getactivewindow"}"#)
+            ),
+            (json!(1), json!(INVALID_REQUEST))
+        );
+        assert_eq!(
+            error(session.handle_line(r#"{"jsonrpc": "2.0", "id": 2, "params": []}"#)),
+            (json!(2), json!(INVALID_REQUEST))
+        );
+        assert_eq!(
+            error(session.handle_line(
+                r#"{"jsonrpc": "2.0", "id": 3, "method": "subscribe", "params": ["window_moved"]}"#
+            )),
+            (json!(3), json!(INVALID_PARAMS))
+        );
+        assert!(session.subscription.is_none());
+        // Notifications get no response, even if they fail.
+        assert_eq!(
+            session.handle_line(r#"{"jsonrpc": "2.0", "method": "subscribe", "params": ["x"]}"#),
+            None
+        );
+        assert_eq!(
+            session.handle_line(r#"{"jsonrpc": "2.0", "method": "unsubscribe"}"#),
+            None
+        );
+    }
+
+    // The window stack which a request leaves behind is used by the next one.
+    #[test]
+    fn window_stack() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        // A stand-in for KWin, which records the scripts, and has them report
+        // a window stack when they are run.
+        let scripts = Arc::new(Mutex::new(vec![]));
+        let loaded = scripts.clone();
+        let script_conn = bus.connect();
+        bus.serve("org.kde.KWin", move |message: &Message| {
+            match message.member().as_deref() {
+                Some("loadScript") => {
+                    let path: String = message.get1().unwrap();
+                    loaded
+                        .lock()
+                        .unwrap()
+                        .push(std::fs::read_to_string(path).unwrap());
+                    Some(message.method_return().append1(1))
+                }
+                Some("run") => {
+                    let script = loaded.lock().unwrap().last().unwrap().clone();
+                    let address = script.split("callDBus(\"").nth(1)?.split('"').next()?;
+                    for (method, arg) in [("window_stack", "[\"{a}\"]"), ("finished", "")] {
+                        let call =
+                            Message::new_method_call(address, "/", "org.kde.kwin.Script", method)
+                                .unwrap()
+                                .append1(arg);
+                        script_conn.send(call).unwrap();
+                    }
+                    script_conn.channel().flush();
+                    Some(message.method_return())
+                }
+                _ => Some(message.method_return().append1(true)),
+            }
+        });
+        let (mut session, _client) = session(&bus);
+        for id in [1, 2] {
+            let response = session
+                .handle_line(
+                    &json!({ "jsonrpc": "2.0", "id": id, "method": "windowminimize" }).to_string(),
+                )
+                .unwrap();
+            assert_eq!(response["result"], json!([]), "{response}");
+        }
+        let scripts = scripts.lock().unwrap();
+        assert!(!scripts[0].contains("{a}"));
+        assert!(scripts[1].contains("var window_stack = [\"{a}\"]"));
+        assert_eq!(session.window_stack, ["{a}"]);
+    }
+
+    #[test]
+    fn stale_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("socket");
+        drop(bind(&path).unwrap());
+        // Left behind by the listener.
+        assert!(path.exists());
+        let listener = bind(&path).unwrap();
+        assert!(bind(&path)
+            .unwrap_err()
+            .to_string()
+            .contains("already in use"));
+        drop(listener);
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "data").unwrap();
+        assert!(bind(&file)
+            .unwrap_err()
+            .to_string()
+            .contains("isn't a socket"));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "data");
+    }
+}
@@ -0,0 +1,436 @@
+// A registry of the shortcuts registered by kdotool, kept in
+// $XDG_STATE_HOME/kdotool/shortcuts.json. KWin can't tell which shortcut or
+// command a script is for, so this is the only record of them.
+//
+// `kdotool shortcuts apply FILE` brings the registered shortcuts in line with a
+// TOML file of shortcuts:
+//
+//   [[shortcut]]
+//   name = "minimize"
+//   key = "Meta+Shift+M"
+//   chain = ["getactivewindow", "windowminimize"]
+//
+// Instead of, or as well as a key, a shortcut can have a screen_edge, a
+// touch_edge and a menu_entry in the window menu.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context};
+use dbus::blocking::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::kglobalaccel::unregister_shortcut_key;
+use crate::scripting::{is_script_loaded, unload_script};
+use crate::{register_shortcut, Globals, Parser};
+
+// Besides keyboard shortcuts, these are also the scripts registered for screen
+// edges and window menu entries.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Shortcut {
+    pub name: String,
+    #[serde(default)]
+    pub shortcut: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub screen_edge: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub touch_edge: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub menu_entry: Option<String>,
+    // Where the daemon passes on the output of the script, see output.rs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_output: Option<String>,
+    pub chain: Vec<String>,
+    // Installed scripts are loaded by KWin itself, so their IDs aren't known.
+    pub script_id: Option<i32>,
+    #[serde(default)]
+    pub installed: bool,
+}
+
+impl Shortcut {
+    // The key, the edges and the menu entry the script is registered for.
+    pub fn triggers(&self) -> Vec<String> {
+        let mut triggers = vec![];
+        if !self.shortcut.is_empty() {
+            triggers.push(format!("shortcut {}", self.shortcut));
+        }
+        if let Some(edge) = &self.screen_edge {
+            triggers.push(format!("screen edge {edge}"));
+        }
+        if let Some(edge) = &self.touch_edge {
+            triggers.push(format!("touch edge {edge}"));
+        }
+        if let Some(title) = &self.menu_entry {
+            triggers.push(format!("menu entry {title}"));
+        }
+        triggers
+    }
+}
+
+fn registry_path() -> anyhow::Result<PathBuf> {
+    let state_home = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME").ok_or(anyhow!("HOME is not set"))?)
+            .join(".local")
+            .join("state"),
+    };
+    Ok(state_home.join("kdotool").join("shortcuts.json"))
+}
+
+pub fn load_shortcuts() -> anyhow::Result<Vec<Shortcut>> {
+    let path = registry_path()?;
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+fn save_shortcuts(shortcuts: &[Shortcut]) -> anyhow::Result<()> {
+    let path = registry_path()?;
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(dir)?;
+    // Replace the registry in one go, so that it's never left half written.
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(serde_json::to_string_pretty(shortcuts)?.as_bytes())?;
+    file.persist(path)?;
+    Ok(())
+}
+
+// Record a shortcut, replacing any previous one with the same name.
+pub fn record_shortcut(shortcut: Shortcut) -> anyhow::Result<()> {
+    let mut shortcuts = load_shortcuts()?;
+    shortcuts.retain(|s| s.name != shortcut.name);
+    shortcuts.push(shortcut);
+    save_shortcuts(&shortcuts)
+}
+
+pub fn forget_shortcut(name: &str) -> anyhow::Result<()> {
+    let mut shortcuts = load_shortcuts()?;
+    let count = shortcuts.len();
+    shortcuts.retain(|s| s.name != name);
+    if shortcuts.len() != count {
+        save_shortcuts(&shortcuts)?;
+    }
+    Ok(())
+}
+
+// Unload a shortcut script, and forget its key, so the key can be used again.
+pub fn remove_shortcut(conn: &Connection, name: &str) -> anyhow::Result<()> {
+    unload_script(conn, name)?;
+    unregister_shortcut_key(conn, name);
+    forget_shortcut(name)
+}
+
+// Print the registered shortcuts. Those whose scripts aren't loaded in KWin any
+// more, e.g. after KWin was restarted, are marked as stale.
+pub fn list_shortcuts(conn: &Connection, json: bool) -> anyhow::Result<()> {
+    let mut result = vec![];
+    for shortcut in load_shortcuts()? {
+        let stale = !is_script_loaded(conn, &shortcut.name)?;
+        result.push((shortcut, stale));
+    }
+    if json {
+        let list: Vec<serde_json::Value> = result
+            .into_iter()
+            .map(|(shortcut, stale)| {
+                let mut value = serde_json::to_value(shortcut).unwrap();
+                value["stale"] = stale.into();
+                value
+            })
+            .collect();
+        println!("{}", serde_json::to_string(&list)?);
+    } else {
+        for (shortcut, stale) in result {
+            println!(
+                "name:{} shortcut:{} screen_edge:{} touch_edge:{} menu_entry:{} script_id:{} installed:{} state:{} command:{}",
+                shortcut.name,
+                shortcut.shortcut,
+                shortcut.screen_edge.as_deref().unwrap_or_default(),
+                shortcut.touch_edge.as_deref().unwrap_or_default(),
+                shortcut.menu_entry.as_deref().unwrap_or_default(),
+                shortcut
+                    .script_id
+                    .map_or("none".to_string(), |id| id.to_string()),
+                shortcut.installed,
+                if stale { "stale" } else { "loaded" },
+                shortcut.chain.join(" ")
+            );
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShortcutFile {
+    #[serde(default)]
+    shortcut: Vec<ShortcutEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShortcutEntry {
+    name: String,
+    #[serde(default)]
+    key: String,
+    screen_edge: Option<String>,
+    touch_edge: Option<String>,
+    menu_entry: Option<String>,
+    chain: Chain,
+}
+
+// A chain is either a list of arguments, or a string which is split at
+// whitespace.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Chain {
+    Args(Vec<String>),
+    Line(String),
+}
+
+impl Chain {
+    fn args(&self) -> Vec<String> {
+        match self {
+            Chain::Args(args) => args.clone(),
+            Chain::Line(line) => line.split_whitespace().map(String::from).collect(),
+        }
+    }
+}
+
+enum Change {
+    Add(Shortcut),
+    Replace(Shortcut),
+    Remove(String),
+    Unchanged(String),
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Add(s) => write!(
+                f,
+                "add {}: {}: {}",
+                s.name,
+                s.triggers().join(", "),
+                s.chain.join(" ")
+            ),
+            Change::Replace(s) => write!(
+                f,
+                "replace {}: {}: {}",
+                s.name,
+                s.triggers().join(", "),
+                s.chain.join(" ")
+            ),
+            Change::Remove(name) => write!(f, "remove {name}"),
+            Change::Unchanged(name) => write!(f, "unchanged {name}"),
+        }
+    }
+}
+
+// Compare the shortcuts in a file with the registered ones. Installed
+// shortcuts are left alone. is_loaded tells whether a script is loaded in KWin.
+fn plan(
+    registered: Vec<Shortcut>,
+    file: ShortcutFile,
+    mut is_loaded: impl FnMut(&str) -> anyhow::Result<bool>,
+) -> anyhow::Result<Vec<Change>> {
+    let registered: Vec<Shortcut> = registered.into_iter().filter(|s| !s.installed).collect();
+    let mut changes = vec![];
+    for old in &registered {
+        if !file.shortcut.iter().any(|entry| entry.name == old.name) {
+            changes.push(Change::Remove(old.name.clone()));
+        }
+    }
+    for (i, entry) in file.shortcut.iter().enumerate() {
+        if file.shortcut[..i].iter().any(|e| e.name == entry.name) {
+            return Err(anyhow!("duplicate shortcut name '{}'", entry.name));
+        }
+        let new = Shortcut {
+            name: entry.name.clone(),
+            shortcut: entry.key.clone(),
+            screen_edge: entry.screen_edge.clone(),
+            touch_edge: entry.touch_edge.clone(),
+            menu_entry: entry.menu_entry.clone(),
+            on_output: None,
+            chain: entry.chain.args(),
+            script_id: None,
+            installed: false,
+        };
+        if new.triggers().is_empty() {
+            return Err(anyhow!(
+                "shortcut '{}' needs a key, screen_edge, touch_edge or menu_entry",
+                entry.name
+            ));
+        }
+        if new.chain.is_empty() {
+            return Err(anyhow!("empty chain in shortcut '{}'", entry.name));
+        }
+        changes.push(match registered.iter().find(|s| s.name == new.name) {
+            Some(old)
+                if old.triggers() == new.triggers()
+                    && old.chain == new.chain
+                    && is_loaded(&old.name)? =>
+            {
+                Change::Unchanged(new.name)
+            }
+            Some(_) => Change::Replace(new),
+            None if is_loaded(&new.name)? => {
+                return Err(anyhow!(
+                    "a script named '{}' is already loaded, but wasn't registered as a shortcut",
+                    new.name
+                ));
+            }
+            None => Change::Add(new),
+        });
+    }
+    Ok(changes)
+}
+
+fn apply(conn: &Connection, globals: &Globals, path: &str, dry_run: bool) -> anyhow::Result<()> {
+    let contents = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+    let file: ShortcutFile =
+        toml::from_str(&contents).with_context(|| format!("parsing {path}"))?;
+    for change in plan(load_shortcuts()?, file, |name| is_script_loaded(conn, name))? {
+        println!("{change}");
+        if dry_run {
+            continue;
+        }
+        match change {
+            Change::Remove(name) => {
+                remove_shortcut(conn, &name)?;
+            }
+            Change::Replace(shortcut) | Change::Add(shortcut) => {
+                remove_shortcut(conn, &shortcut.name)?;
+                register_shortcut(conn, globals, &shortcut)
+                    .with_context(|| format!("in shortcut '{}'", shortcut.name))?;
+            }
+            Change::Unchanged(_) => {}
+        }
+    }
+    Ok(())
+}
+
+// `kdotool shortcuts apply [--dry-run] FILE`
+pub fn shortcuts_command(
+    conn: &Connection,
+    globals: &Globals,
+    mut parser: Parser,
+    mut dry_run: bool,
+) -> anyhow::Result<()> {
+    use lexopt::prelude::*;
+    let mut args = vec![];
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('n') | Long("dry-run") => {
+                dry_run = true;
+            }
+            Value(val) => {
+                args.push(val.string()?);
+            }
+            _ => {
+                return Err(arg.unexpected().into());
+            }
+        }
+    }
+    match args.as_slice() {
+        [command, path] if command == "apply" => apply(conn, globals, path, dry_run),
+        _ => Err(anyhow!("usage: kdotool shortcuts apply [--dry-run] FILE")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registered(name: &str, key: &str, chain: &str, installed: bool) -> Shortcut {
+        Shortcut {
+            name: name.into(),
+            shortcut: key.into(),
+            screen_edge: None,
+            touch_edge: None,
+            menu_entry: None,
+            on_output: None,
+            chain: chain.split_whitespace().map(String::from).collect(),
+            script_id: None,
+            installed,
+        }
+    }
+
+    fn plan_toml(
+        registered: Vec<Shortcut>,
+        toml: &str,
+        loaded: &[&str],
+    ) -> anyhow::Result<Vec<String>> {
+        let file = toml::from_str(toml)?;
+        let changes = plan(registered, file, |name| Ok(loaded.contains(&name)))?;
+        Ok(changes.iter().map(|change| change.to_string()).collect())
+    }
+
+    #[test]
+    fn changes() {
+        let changes = plan_toml(
+            vec![
+                registered("same", "Meta+A", "getactivewindow windowminimize", false),
+                registered("rekeyed", "Meta+B", "getactivewindow", false),
+                registered("stale", "Meta+C", "getactivewindow", false),
+                registered("gone", "Meta+D", "getactivewindow", false),
+                registered("installed", "Meta+E", "getactivewindow", true),
+            ],
+            r#"
+                [[shortcut]]
+                name = "same"
+                key = "Meta+A"
+                chain = ["getactivewindow", "windowminimize"]
+
+                [[shortcut]]
+                name = "rekeyed"
+                key = "Meta+Shift+B"
+                chain = "getactivewindow"
+
+                [[shortcut]]
+                name = "stale"
+                key = "Meta+C"
+                chain = "getactivewindow"
+
+                [[shortcut]]
+                name = "new"
+                screen_edge = "top"
+                chain = "getactivewindow  windowclose"
+            "#,
+            &["same", "rekeyed", "gone", "installed"],
+        )
+        .unwrap();
+        assert_eq!(
+            changes,
+            [
+                "remove gone",
+                "unchanged same",
+                "replace rekeyed: shortcut Meta+Shift+B: getactivewindow",
+                "replace stale: shortcut Meta+C: getactivewindow",
+                "add new: screen edge top: getactivewindow windowclose",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_files() {
+        let shortcut =
+            |name: &str, fields: &str| format!("[[shortcut]]\nname = \"{name}\"\n{fields}\n");
+        let key = "key = \"Meta+A\"\nchain = \"getactivewindow\"";
+        let errors = [
+            (
+                shortcut("a", key) + &shortcut("a", key),
+                "duplicate shortcut name 'a'",
+            ),
+            (shortcut("a", "chain = \"getactivewindow\""), "needs a key"),
+            (shortcut("a", "key = \"Meta+A\"\nchain = []"), "empty chain"),
+            (shortcut("loaded", key), "is already loaded"),
+        ];
+        for (toml, error) in errors {
+            let result = plan_toml(vec![], &toml, &["loaded"]);
+            let message = result.unwrap_err().to_string();
+            assert!(message.contains(error), "{message}");
+        }
+        assert!(plan_toml(vec![], &shortcut("a", "kee = \"Meta+A\""), &[]).is_err());
+    }
+}
@@ -3,47 +3,97 @@ pub const SCRIPT_HEADER: &str = r#"
 print("{{{marker}}} START");
 {{/if}}
 
+// Registered scripts outlive the kdotool process that loaded them, so without
+// --on-output their output only goes to the KWin log.
 function output_debug(message) {
     {{#if debug}}
     print("{{{marker}}} DEBUG", message);
+    {{#if (or on_output (not registered))}}
     callDBus("{{{dbus_addr}}}", "/", "", "debug", message.toString());
     {{/if}}
+    {{/if}}
 }
 
 function output_error(message) {
     print("{{{marker}}} ERROR", message);
-    callDBus("{{{dbus_addr}}}", "/", "", "error", message.toString());
+    {{#if (or on_output (not registered))}}
+    callDBus("{{{dbus_addr}}}", "/", "", "error", message.toString(){{#if on_output}}, {{{on_output}}}, {{{script_name_json}}}{{/if}});
+    {{/if}}
 }
 
 function output_result(message) {
     if (message == null) {
         message = "null";
     }
-    {{#if debug}}
+    {{#if (or debug registered)}}
     print("{{{marker}}} RESULT", message);
     {{/if}}
-    callDBus("{{{dbus_addr}}}", "/", "", "result", message.toString());
+    {{#if (or on_output (not registered))}}
+    callDBus("{{{dbus_addr}}}", "/", "", "result", message.toString(){{#if on_output}}, {{{on_output}}}, {{{script_name_json}}}{{/if}});
+    {{/if}}
+}
+
+function finish() {
+    {{#unless registered}}
+    callDBus("{{{dbus_addr}}}", "/", "", "finished", "");
+    {{/unless}}
 }
 
 {{#if kde5}}
-workspace_windowList                  = () => workspace.clientList();
-workspace_activeWindow                = () => workspace.activeClient;
-workspace_setActiveWindow             = (window) => { workspace.activeClient = window; };
-workspace_raiseWindow                 = (window) => { output_error("`windowraise` unsupported in KDE 5"); };
-workspace_currentDesktop              = () => workspace.currentDesktop;
-workspace_setCurrentDesktop           = (desktop) => { workspace.currentDesktop = desktop; };
-workspace_numDesktops                 = () => workspace.desktops;
-workspace_setNumDesktops              = (n) => { workspace.desktops = n };
-window_x11DesktopIds                  = (window) => window.x11DesktopIds;
-window_setX11DesktopId                = (window, id) => { window.desktop = id; };
-window_screen                         = (window) => window.screen;
+const workspace_windowList                  = () => workspace.clientList();
+const workspace_activeWindow                = () => workspace.activeClient;
+const workspace_setActiveWindow             = (window) => { workspace.activeClient = window; };
+// There is no raiseWindow() in KDE 5, but activating a window raises it.
+const workspace_raiseWindow                 = (window) => { workspace.activeClient = window; };
+const workspace_lowerWindow                 = (window) => {
+    let active = workspace.activeClient;
+    workspace.activeClient = window;
+    workspace.slotWindowLower();
+    if (active != null && active != window) {
+        workspace.activeClient = active;
+    }
+};
+const workspace_stackingOrder               = () => workspace.clientList().sort((a, b) => a.stackingOrder - b.stackingOrder);
+const workspace_currentDesktop              = () => workspace.currentDesktop;
+const workspace_setCurrentDesktop           = (desktop) => { workspace.currentDesktop = desktop; };
+const workspace_numDesktops                 = () => workspace.desktops;
+const workspace_setNumDesktops              = (n) => { workspace.desktops = n };
+const workspace_desktopList                 = () => Array.from({ length: workspace.desktops }, (_, i) => ({ number: i + 1, name: workspace.desktopName(i + 1), id: "" }));
+const window_x11DesktopIds                  = (window) => window.x11DesktopIds;
+const window_setX11DesktopId                = (window, id) => { window.desktop = id; };
+const window_addX11DesktopId                = (window, id) => { output_error("`add_desktop_for_window` unsupported in KDE 5"); };
+const window_removeX11DesktopId             = (window, id) => { output_error("`remove_desktop_for_window` unsupported in KDE 5"); };
+const window_screen                         = (window) => window.screen;
+const window_output                         = (window) => window.screen;
+const workspace_outputs                     = () => Array.from({ length: workspace.numScreens }, (_, i) => i);
+const workspace_sendWindowToOutput          = (window, output) => { workspace.sendClientToScreen(window, output); };
+const output_name                           = (output) => `${output}`;
+const output_scale                          = (output) => 1;
+const workspace_activeOutput                = () => workspace.activeScreen;
+const workspace_outputAt                    = (pos) => workspace_outputs().find((o) => rect_contains(output_area(o, "output"), pos));
 {{else}}
-workspace_windowList                  = () => workspace.windowList();
-workspace_activeWindow                = () => workspace.activeWindow;
-workspace_setActiveWindow             = (window) => { workspace.activeWindow = window; };
-workspace_raiseWindow                 = (window) => { workspace.raiseWindow(window); };
-workspace_currentDesktop              = () => workspace.currentDesktop.x11DesktopNumber;
-workspace_setCurrentDesktop           = (id) => {
+const workspace_windowList                  = () => workspace.windowList();
+const workspace_activeWindow                = () => workspace.activeWindow;
+const workspace_setActiveWindow             = (window) => { workspace.activeWindow = window; };
+const workspace_raiseWindow                 = (window) => { workspace.raiseWindow(window); };
+// There is no lowerWindow(), so raise the other normal windows which share a
+// desktop and an activity with the window instead, from the bottom to the top
+// to keep their order. An empty list means all desktops or activities.
+const workspace_lowerWindow                 = (window) => {
+    let shared = (a, b) => a.length == 0 || b.length == 0 || a.some((x) => b.indexOf(x) >= 0);
+    let order = workspace.stackingOrder;
+    for (let i = 0; i < order.length; i++) {
+        let w = order[i];
+        if (w != window && w.normalWindow
+            && (w.onAllDesktops || window.onAllDesktops || shared(w.desktops, window.desktops))
+            && shared(w.activities, window.activities)) {
+            workspace.raiseWindow(w);
+        }
+    }
+};
+const workspace_stackingOrder               = () => workspace.stackingOrder;
+const workspace_currentDesktop              = () => workspace.currentDesktop.x11DesktopNumber;
+const workspace_setCurrentDesktop           = (id) => {
     let d = workspace.desktops.find((d) => d.x11DesktopNumber == id);
     if (d) {
         workspace.currentDesktop = d;
@@ -51,10 +101,37 @@ workspace_setCurrentDesktop           = (id) => {
         output_error(`Invalid desktop number ${id}`);
     }
 };
-workspace_numDesktops                 = () => workspace.desktops.length;
-workspace_setNumDesktops              = (n) => { output_error("`set_num_desktops` unsupported in KDE 6"); };
-window_x11DesktopIds                  = (window) => window.desktops.map((d) => d.x11DesktopNumber);
-window_setX11DesktopId                = (window, id) => {
+const workspace_numDesktops                 = () => workspace.desktops.length;
+const workspace_setNumDesktops              = (n) => {
+    while (workspace.desktops.length < n) {
+        workspace.createDesktop(workspace.desktops.length, "");
+    }
+    while (workspace.desktops.length > Math.max(n, 1)) {
+        workspace.removeDesktop(workspace.desktops[workspace.desktops.length - 1]);
+    }
+};
+const workspace_desktopList                 = () => workspace.desktops.map((d) => ({ number: d.x11DesktopNumber, name: d.name, id: d.id }));
+const workspace_createDesktop               = (position, name) => {
+    workspace.createDesktop(position > 0 ? position - 1 : workspace.desktops.length, name);
+};
+const workspace_removeDesktop               = (id) => {
+    let d = workspace.desktops.find((d) => d.x11DesktopNumber == id);
+    if (d) {
+        workspace.removeDesktop(d);
+    } else {
+        output_error(`Invalid desktop number ${id}`);
+    }
+};
+const workspace_renameDesktop               = (id, name) => {
+    let d = workspace.desktops.find((d) => d.x11DesktopNumber == id);
+    if (d) {
+        d.name = name;
+    } else {
+        output_error(`Invalid desktop number ${id}`);
+    }
+};
+const window_x11DesktopIds                  = (window) => window.desktops.map((d) => d.x11DesktopNumber);
+const window_setX11DesktopId                = (window, id) => {
     if (id < 0) {
         window.desktops = [workspace.currentDesktop];
     } else {
@@ -66,20 +143,290 @@ window_setX11DesktopId                = (window, id) => {
         }
     }
 };
-window_screen                         = (window) => { output_error("`search --screen` unsupported in KDE 6"); };
+const window_addX11DesktopId                = (window, id) => {
+    let d = workspace.desktops.find((d) => d.x11DesktopNumber == id);
+    if (!window.onAllDesktops && window.desktops.indexOf(d) < 0) {
+        window.desktops = window.desktops.concat([d]);
+    }
+};
+const window_removeX11DesktopId             = (window, id) => {
+    let current = window.onAllDesktops ? workspace.desktops : window.desktops;
+    let desktops = current.filter((d) => d.x11DesktopNumber != id);
+    if (desktops.length == 0) {
+        output_error(`Window ${window.internalId} is only on desktop ${id}`);
+    } else {
+        window.desktops = desktops;
+    }
+};
+const window_screen                         = (window) => { output_error("`search --screen` unsupported in KDE 6"); };
+const window_output                         = (window) => window.output;
+const workspace_outputs                     = () => workspace.screens;
+const workspace_sendWindowToOutput          = (window, output) => { workspace.sendClientToScreen(window, output); };
+const output_name                           = (output) => output.name;
+const output_scale                          = (output) => output.devicePixelRatio;
+const workspace_activeOutput                = () => workspace.activeScreen;
+const workspace_outputAt                    = (pos) => workspace.screenAt(pos);
 {{/if}}
 
-function run() {
-    var window_stack = [];
+// Find a desktop by number, "name:NAME" or UUID (KDE 6 only). Returns the
+// desktop number.
+function find_desktop(spec) {
+    let desktops = workspace_desktopList();
+    let d;
+    if (spec.startsWith("name:")) {
+        d = desktops.find((d) => d.name == spec.substring(5));
+    } else if (/^[0-9]+$/.test(spec)) {
+        d = desktops.find((d) => d.number == parseInt(spec));
+    } else {
+        d = desktops.find((d) => d.id != "" && d.id == spec);
+    }
+    if (!d) {
+        output_error(`Invalid desktop '${spec}'`);
+        return null;
+    }
+    return d.number;
+}
+
+function desktop_name(number) {
+    let d = workspace_desktopList().find((d) => d.number == number);
+    return d ? d.name : null;
+}
+
+function find_activity(id) {
+    if (workspace.activities.indexOf(id) < 0) {
+        output_error(`Invalid activity '${id}'`);
+        return null;
+    }
+    return id;
+}
+
+// An empty list means the window is on all activities.
+function window_onActivity(window, id) {
+    return window.activities.length == 0 || window.activities.indexOf(id) >= 0;
+}
+
+// Desktop numbers are 1-based. Without wrapping, we stay at the first or last
+// desktop, or at the edge of the desktop grid.
+function desktop_relative(n, wrap) {
+    let count = workspace_numDesktops();
+    let index = workspace_currentDesktop() - 1 + n;
+    if (wrap) {
+        index = ((index % count) + count) % count;
+    } else {
+        index = Math.max(0, Math.min(index, count - 1));
+    }
+    return index + 1;
+}
+
+function desktop_in_direction(direction, wrap) {
+    let count = workspace_numDesktops();
+    let cols = workspace.desktopGridWidth;
+    let index = workspace_currentDesktop() - 1;
+    let row = Math.floor(index / cols);
+    let col = index % cols;
+    if (direction == "left" || direction == "right") {
+        // The last row may be incomplete.
+        let len = Math.min(cols, count - row * cols);
+        col += direction == "left" ? -1 : 1;
+        if (wrap) {
+            col = (col + len) % len;
+        }
+        if (col < 0 || col >= len) {
+            return index + 1;
+        }
+    } else {
+        let len = Math.ceil((count - col) / cols);
+        row += direction == "up" ? -1 : 1;
+        if (wrap) {
+            row = (row + len) % len;
+        }
+        if (row < 0 || row >= len) {
+            return index + 1;
+        }
+    }
+    return row * cols + col + 1;
+}
+
+function output_area(output, area) {
+    switch (area) {
+    case "virtual":
+        return { x: 0, y: 0, width: workspace.virtualScreenSize.width, height: workspace.virtualScreenSize.height };
+    case "output":
+        return workspace.clientArea(KWin.ScreenArea, output, workspace.currentDesktop);
+    default:
+        return workspace.clientArea(KWin.MaximizeArea, output, workspace.currentDesktop);
+    }
+}
+
+function window_area(window, area) {
+    return output_area(window_output(window), area);
+}
+
+// Find an output by name, index, or "next"/"prev" relative to the given output.
+function find_output(spec, current) {
+    let outputs = workspace_outputs();
+    let index = outputs.indexOf(current);
+    if (spec == "next") {
+        index = (index + 1) % outputs.length;
+    } else if (spec == "prev") {
+        index = (index + outputs.length - 1) % outputs.length;
+    } else if (/^[0-9]+$/.test(spec)) {
+        index = parseInt(spec);
+    } else {
+        index = outputs.findIndex((o) => output_name(o) == spec);
+    }
+    if (index < 0 || index >= outputs.length) {
+        output_error(`Invalid output '${spec}'`);
+        return null;
+    }
+    return outputs[index];
+}
+
+function rect_contains(rect, pos) {
+    return pos.x >= rect.x && pos.x < rect.x + rect.width && pos.y >= rect.y && pos.y < rect.y + rect.height;
+}
+
+function rect_string(rect) {
+    return `${rect.width}x${rect.height}+${rect.x}+${rect.y}`;
+}
+
+function rect_json(rect) {
+    return { x: rect.x, y: rect.y, width: rect.width, height: rect.height };
+}
+
+// The client geometry excludes window decorations. Fall back to the buffer
+// geometry where clientGeometry isn't exposed.
+function window_geometry(window, client) {
+    if (!client) {
+        return window.frameGeometry;
+    }
+    return window.clientGeometry !== undefined ? window.clientGeometry : window.bufferGeometry;
+}
+
+function window_setGeometry(window, rect, client) {
+    if (client) {
+        let f = window.frameGeometry;
+        let c = window_geometry(window, true);
+        rect = {
+            x: rect.x - (c.x - f.x),
+            y: rect.y - (c.y - f.y),
+            width: rect.width + (f.width - c.width),
+            height: rect.height + (f.height - c.height),
+        };
+    }
+    window.frameGeometry = rect;
+}
+
+// Convert between KWin's logical coordinates and the coordinates used on the
+// command line. Physical coordinates are scaled relative to the top-left
+// corner of the output. A rect is converted with the scale of the output its
+// top-left corner is on, or of the given output if the corner is on none.
+function point_output(pos, output) {
+    let o = workspace_outputAt(pos);
+    return o == null ? output : o;
+}
+
+// The output whose area in physical coordinates contains a point. Scaled
+// areas can overlap, so the given output is preferred.
+function user_point_output(pos, output) {
+    {{#if physical_coords}}
+    let area = (o) => {
+        let s = output_scale(o);
+        let a = output_area(o, "output");
+        return { x: a.x, y: a.y, width: a.width * s, height: a.height * s };
+    };
+    if (output != null && rect_contains(area(output), pos)) {
+        return output;
+    }
+    let o = workspace_outputs().find((o) => rect_contains(area(o), pos));
+    return o === undefined ? output : o;
+    {{else}}
+    return output;
+    {{/if}}
+}
+
+function to_user_point(pos, output) {
+    {{#if physical_coords}}
+    output = point_output(pos, output);
+    let s = output_scale(output);
+    let o = output_area(output, "output");
+    return { x: Math.round(o.x + (pos.x - o.x) * s), y: Math.round(o.y + (pos.y - o.y) * s) };
+    {{else}}
+    return { x: pos.x, y: pos.y };
+    {{/if}}
+}
+
+function to_user_rect(rect, output) {
+    {{#if physical_coords}}
+    output = point_output(rect, output);
+    let s = output_scale(output);
+    let p = to_user_point(rect, output);
+    return { x: p.x, y: p.y, width: Math.round(rect.width * s), height: Math.round(rect.height * s) };
+    {{else}}
+    return rect_json(rect);
+    {{/if}}
+}
+
+function from_user_rect(rect, output) {
+    {{#if physical_coords}}
+    output = user_point_output(rect, output);
+    let s = output_scale(output);
+    let o = output_area(output, "output");
+    return { x: o.x + (rect.x - o.x) / s, y: o.y + (rect.y - o.y) / s, width: rect.width / s, height: rect.height / s };
+    {{else}}
+    return rect;
+    {{/if}}
+}
+
+function clamp_rect(rect, area) {
+    rect.width = Math.min(rect.width, area.width);
+    rect.height = Math.min(rect.height, area.height);
+    rect.x = Math.max(area.x, Math.min(rect.x, area.x + area.width - rect.width));
+    rect.y = Math.max(area.y, Math.min(rect.y, area.y + area.height - rect.height));
+    return rect;
+}
+
+function run(menu_window) {
+    {{#if window_stack}}
+    var window_stack = {{{window_stack}}}.map((id) => workspace_windowList().find((w) => w.internalId == id)).filter((w) => w != null);
+    {{else}}
+    var window_stack = menu_window ? [menu_window] : [];
+    {{/if}}
+    var saved_window_stacks = {};
 "#;
 
 pub const SCRIPT_FOOTER: &str = r#"
 }
 
+function run_and_finish(menu_window) {
+    try {
+        run(menu_window);
+    } catch (e) {
+        output_error(e);
+        finish();
+    }
+}
+
+{{#if registered}}
+// Only the menu entry is for a window. The other callbacks must not take
+// whatever KWin passes them for one.
 {{#if shortcut}}
-registerShortcut("{{#if script_name}}{{{script_name}}}{{else}}{{{marker}}}{{/if}}", "{{#if script_name}}{{{script_name}}}{{else}}{{{cmdline}}}{{/if}}", "{{{shortcut}}}", run);
+registerShortcut({{{script_name_json}}}, {{{script_name_json}}}, "{{{shortcut}}}", () => run_and_finish());
+{{/if}}
+{{#if screen_edge}}
+registerScreenEdge({{{screen_edge}}}, () => run_and_finish());
+{{/if}}
+{{#if touch_edge}}
+registerTouchScreenEdge({{{touch_edge}}}, () => run_and_finish());
+{{/if}}
+{{#if menu_entry}}
+registerUserActionsMenu((w) => ({
+    text: {{{menu_entry}}},
+    triggered: () => run_and_finish(w),
+}));
+{{/if}}
 {{else}}
-run();
+run_and_finish();
 {{/if}}
 
 {{#if debug}}
@@ -87,44 +434,96 @@ print("{{{marker}}} FINISH");
 {{/if}}
 "#;
 
+// The resident script of `kdotool daemon`, which runs the scripts generated by
+// the daemon. Each script runs in its own function scope, so that scripts
+// running at the same time don't interfere.
+pub const DAEMON_SCRIPT: &str = r#"
+function poll() {
+    callDBus("{{{daemon_name}}}", "/", "", "next", function (script) {
+        if (script) {
+            try {
+                new Function(script)();
+            } catch (e) {
+                print("kdotool daemon ERROR", e);
+            }
+        }
+        poll();
+    });
+}
+
+poll();
+"#;
+
+pub const STEP_SUBSCRIBE: &str = r#"
+    output_debug("STEP subscribe")
+    {
+        const send_event = (event, data) => {
+            callDBus("{{{dbus_addr}}}", "/", "", "event", JSON.stringify(Object.assign({ event: event }, data)));
+        };
+        const window_event = (event) => (w) => send_event(event, { window: w ? w.internalId.toString() : null });
+        {{#if kde5}}
+        workspace.clientAdded.connect(window_event("window_added"));
+        workspace.clientRemoved.connect(window_event("window_removed"));
+        workspace.clientActivated.connect(window_event("window_activated"));
+        {{else}}
+        workspace.windowAdded.connect(window_event("window_added"));
+        workspace.windowRemoved.connect(window_event("window_removed"));
+        workspace.windowActivated.connect(window_event("window_activated"));
+        {{/if}}
+        workspace.currentDesktopChanged.connect(() => send_event("desktop_changed", { desktop: workspace_currentDesktop() }));
+        workspace.currentActivityChanged.connect((id) => send_event("activity_changed", { activity: id }));
+    }
+"#;
+
 pub const STEP_SEARCH: &str = r#"
-    output_debug("STEP search {{{search_term}}}")
-    const re = new RegExp(String.raw`{{{search_term}}}`, "i");
-    var t = workspace_windowList();
-    window_stack = [];
-    for (var i=0; i<t.length; i++) {
-        let w = t[i];
-        if ({{#if match_all}}true{{else}}false{{/if}}
-            {{#if match_class}}
-            {{#if match_all}}&&{{else}}||{{/if}}
-            w.resourceClass.search(re) >= 0
-            {{/if}}
-            {{#if match_classname}}
-            {{#if match_all}}&&{{else}}||{{/if}}
-            w.resourceName.search(re) >= 0
-            {{/if}}
-            {{#if match_role}}
-            {{#if match_all}}&&{{else}}||{{/if}}
-            w.windowRole.search(re) >= 0
-            {{/if}}
-            {{#if match_name}}
-            {{#if match_all}}&&{{else}}||{{/if}}
-            w.caption.search(re) >= 0
-            {{/if}}
-            {{#if match_pid}}
-            {{#if match_all}}&&{{else}}||{{/if}}
-            w.pid == {{{pid}}}
-            {{/if}}
-        ) {
-            {{#if match_desktop}}
-            if (window_x11DesktopIds(w).indexOf({{{desktop}}}) < 0) continue;
-            {{/if}}
-            {{#if match_screen}}
-            if (window_screen(w) != {{{screen}}}) continue;
-            {{/if}}
-            window_stack.push(w);
-            if ({{{limit}}} > 0 && window_stack.length >= {{{limit}}}) {
-                break;
+    output_debug("STEP search " + {{{search_term}}})
+    {
+        const re = new RegExp({{{search_term}}}, "i");
+        {{#if match_desktop}}
+        const desktop = find_desktop({{{desktop}}});
+        {{/if}}
+        {{#if match_activity}}
+        const activity = find_activity({{{activity}}});
+        {{/if}}
+        var t = workspace_windowList();
+        window_stack = [];
+        for (var i=0; i<t.length; i++) {
+            let w = t[i];
+            if ({{#if match_all}}true{{else}}false{{/if}}
+                {{#if match_class}}
+                {{#if match_all}}&&{{else}}||{{/if}}
+                w.resourceClass.search(re) >= 0
+                {{/if}}
+                {{#if match_classname}}
+                {{#if match_all}}&&{{else}}||{{/if}}
+                w.resourceName.search(re) >= 0
+                {{/if}}
+                {{#if match_role}}
+                {{#if match_all}}&&{{else}}||{{/if}}
+                w.windowRole.search(re) >= 0
+                {{/if}}
+                {{#if match_name}}
+                {{#if match_all}}&&{{else}}||{{/if}}
+                w.caption.search(re) >= 0
+                {{/if}}
+                {{#if match_pid}}
+                {{#if match_all}}&&{{else}}||{{/if}}
+                w.pid == {{{pid}}}
+                {{/if}}
+            ) {
+                {{#if match_desktop}}
+                if (window_x11DesktopIds(w).indexOf(desktop) < 0) continue;
+                {{/if}}
+                {{#if match_activity}}
+                if (activity == null || !window_onActivity(w, activity)) continue;
+                {{/if}}
+                {{#if match_screen}}
+                if (window_screen(w) != {{{screen}}}) continue;
+                {{/if}}
+                window_stack.push(w);
+                if ({{{limit}}} > 0 && window_stack.length >= {{{limit}}}) {
+                    break;
+                }
             }
         }
     }
@@ -132,17 +531,24 @@ pub const STEP_SEARCH: &str = r#"
 
 pub const STEP_GETACTIVEWINDOW: &str = r#"
     output_debug("STEP getactivewindow")
-    var window_stack = [workspace_activeWindow()];
+    window_stack = [workspace_activeWindow()];
+"#;
+
+pub const STEP_SELECTWINDOW: &str = r#"
+    window_stack = workspace_windowList().filter((w) => w.internalId == native_result);
 "#;
 
 pub const STEP_SAVEWINDOWSTACK: &str = r#"
     output_debug("STEP savewindowstack")
-    var window_stack_{{{name}}} = window_stack;
+    saved_window_stacks[{{{name}}}] = window_stack;
 "#;
 
 pub const STEP_LOADWINDOWSTACK: &str = r#"
     output_debug("STEP loadwindowstack")
-    var window_stack = window_stack_{{{name}}};
+    if (!({{{name}}} in saved_window_stacks)) {
+        throw `Unknown window stack '${ {{{name}}} }'`;
+    }
+    window_stack = saved_window_stacks[{{{name}}}];
 "#;
 
 pub const STEP_ACTION_ON_WINDOW_ID: &str = r#"
@@ -150,7 +556,7 @@ pub const STEP_ACTION_ON_WINDOW_ID: &str = r#"
     var t = workspace_windowList();
     for (var i=0; i<t.length; i++) {
         let w = t[i];
-        if (w.internalId == "{{{window_id}}}") {
+        if (w.internalId == {{{window_id}}}) {
             {{{action}}}
             break;
         }
@@ -169,12 +575,44 @@ pub const STEP_ACTION_ON_STACK_ITEM: &str = r#"
     }
 "#;
 
+// With stacking_order, windows are processed from bottom to top.
 pub const STEP_ACTION_ON_STACK_ALL: &str = r#"
     output_debug("STEP {{{step_name}}}")
-    for (var i=0; i<window_stack.length; i++) {
-        let w = window_stack[i];
-        {{{action}}}
+    {
+        let windows = {{#if stacking_order}}window_stack.slice().sort((a, b) => a.stackingOrder - b.stackingOrder){{else}}window_stack{{/if}};
+        for (var i=0; i<windows.length; i++) {
+            let w = windows[i];
+            {{{action}}}
+        }
+    }
+"#;
+
+// Ask kdotool to run a native step. The rest of the chain runs in the callback,
+// and is closed by STEP_NATIVE_END.
+pub const STEP_NATIVE: &str = r#"
+    output_debug("STEP {{{step_name}}}")
+    callDBus("{{{dbus_addr}}}", "/", "", "native", "{{{native_index}}}", function (reply) {
+    try {
+    if (reply == "") {
+        finish();
+        return;
+    }
+    let native_result = JSON.parse(reply);
+"#;
+
+pub const STEP_NATIVE_END: &str = r#"
+    } catch (e) {
+        output_error(e);
+        finish();
     }
+    });
+"#;
+
+pub const STEP_FINISH: &str = r#"
+    {{#if window_stack}}
+    callDBus("{{{dbus_addr}}}", "/", "", "window_stack", JSON.stringify(window_stack.map((w) => w.internalId.toString())));
+    {{/if}}
+    finish();
 "#;
 
 pub const STEP_LAST_OUTPUT: &str = r#"
@@ -183,37 +621,144 @@ pub const STEP_LAST_OUTPUT: &str = r#"
     }
 "#;
 
+// Touch screen edges can't be corners.
+pub const SCREEN_EDGES: phf::Map<&'static str, &'static str> = phf::phf_map! {
+    "top"           => "KWin.ElectricTop",
+    "top-right"     => "KWin.ElectricTopRight",
+    "right"         => "KWin.ElectricRight",
+    "bottom-right"  => "KWin.ElectricBottomRight",
+    "bottom"        => "KWin.ElectricBottom",
+    "bottom-left"   => "KWin.ElectricBottomLeft",
+    "left"          => "KWin.ElectricLeft",
+    "top-left"      => "KWin.ElectricTopLeft",
+};
+
 pub const WINDOW_ACTIONS: phf::Map<&'static str, &'static str> = phf::phf_map! {
     "getwindowname"         => "output_result(w.caption);",
     "getwindowclassname"    => "output_result(w.resourceClass);",
-    "getwindowgeometry"     => "output_result(`Window ${w.internalId}`); output_result(`  Position: ${w.x},${w.y}{{#if kde5}} (screen: ${window_screen(w)}){{/if}}`); output_result(`  Geometry: ${w.width}x${w.height}`);",
+    "getwindowgeometry"     => r#"
+            let g = to_user_rect(window_geometry(w, {{client}}), window_output(w));
+            output_result(`Window ${w.internalId}`);
+            output_result(`  Position: ${g.x},${g.y}{{#if kde5}} (screen: ${window_screen(w)}){{/if}}`);
+            output_result(`  Geometry: ${g.width}x${g.height}`);
+"#,
     "getwindowid"           => "output_result(w.internalId);",
     "getwindowpid"          => "output_result(w.pid);",
     "windowminimize"        => "w.minimized = true;",
+    "windowunminimize"      => "w.minimized = false;",
+    "windowrestore"         => r#"
+            w.minimized = false;
+            workspace_raiseWindow(w);
+"#,
     "windowraise"           => "workspace_raiseWindow(w);",
+    "windowlower"           => "workspace_lowerWindow(w);",
     "windowclose"           => "w.closeWindow();",
     "windowactivate"        => "workspace_setActiveWindow(w);",
     "windowsize"            => r#"
+            let wo = window_output(w);
+            let a = to_user_rect(window_area(w, "{{{area}}}"), wo);
             output_debug(`Window: ${w.frameGeometry}`);
-            output_debug(`Screen: ${workspace.virtualScreenSize}`);
-            let q = Object.assign({}, w.frameGeometry);
-            {{#if x_percent}}q.width=workspace.virtualScreenSize.width*{{{x_percent}}}/100;{{/if}}
-            {{#if y_percent}}q.height=workspace.virtualScreenSize.height*{{{y_percent}}}/100;{{/if}}
+            output_debug(`Area: ${a.x},${a.y} ${a.width}x${a.height}`);
+            let q = to_user_rect(window_geometry(w, {{client}}), wo);
+            {{#if x_percent}}q.width=a.width*{{{x_percent}}}/100;{{/if}}
+            {{#if y_percent}}q.height=a.height*{{{y_percent}}}/100;{{/if}}
             {{#if x}}q.width={{{x}}};{{/if}}
             {{#if y}}q.height={{{y}}};{{/if}}
-            w.frameGeometry = q;
+            {{#if clamp}}q = clamp_rect(q, a);{{/if}}
+            window_setGeometry(w, from_user_rect(q, wo), {{client}});
 "#,
     "windowmove"            => r#"
+            {{#if output}}
+            let o = find_output({{{output}}}, window_output(w));
+            if (o != null && o != window_output(w)) {
+                let from = window_area(w, "{{{area}}}");
+                let r = Object.assign({}, w.frameGeometry);
+                workspace_sendWindowToOutput(w, o);
+                let to = output_area(o, "{{{area}}}");
+                output_debug(`Output: ${output_name(o)} ${to.x},${to.y} ${to.width}x${to.height}`);
+                {{#if keep_relative}}
+                r.x = to.x + (r.x - from.x) * to.width / from.width;
+                r.y = to.y + (r.y - from.y) * to.height / from.height;
+                r.width = r.width * to.width / from.width;
+                r.height = r.height * to.height / from.height;
+                {{else}}
+                r = clamp_rect(Object.assign({}, w.frameGeometry), to);
+                {{/if}}
+                w.frameGeometry = r;
+            }
+            {{/if}}
+            let wo = window_output(w);
+            let a = to_user_rect(window_area(w, "{{{area}}}"), wo);
             output_debug(`Window: ${w.frameGeometry}`);
-            output_debug(`Screen: ${workspace.virtualScreenSize}`);
-            {{#if x_percent}}w.frameGeometry.x={{#if relative}}w.x+{{/if}}workspace.virtualScreenSize.width*{{{x_percent}}}/100;{{/if}}
-            {{#if y_percent}}w.frameGeometry.y={{#if relative}}w.y+{{/if}}workspace.virtualScreenSize.height*{{{y_percent}}}/100;{{/if}}
-            {{#if x}}w.frameGeometry.x={{#if relative}}w.x+{{/if}}{{{x}}};{{/if}}
-            {{#if y}}w.frameGeometry.y={{#if relative}}w.y+{{/if}}{{{y}}};{{/if}}
+            output_debug(`Area: ${a.x},${a.y} ${a.width}x${a.height}`);
+            let q = to_user_rect(window_geometry(w, {{client}}), wo);
+            {{#if x_percent}}q.x={{#if relative}}q.x{{else}}a.x{{/if}}+a.width*{{{x_percent}}}/100;{{/if}}
+            {{#if y_percent}}q.y={{#if relative}}q.y{{else}}a.y{{/if}}+a.height*{{{y_percent}}}/100;{{/if}}
+            {{#if x}}q.x={{#if relative}}q.x+{{/if}}{{{x}}};{{/if}}
+            {{#if y}}q.y={{#if relative}}q.y+{{/if}}{{{y}}};{{/if}}
+            {{#if clamp}}q = clamp_rect(q, a);{{/if}}
+            window_setGeometry(w, from_user_rect(q, wo), {{client}});
 "#,
     "windowstate"           => "{{{windowstate}}}",
-    "get_desktop_for_window"=> "output_result(window_x11DesktopIds(w)[0]);",
-    "set_desktop_for_window"=> "window_setX11DesktopId(w, {{{desktop_id}}})",
+    "get_desktop_for_window"=> r#"
+            {{#if kde5}}
+            // Keep the single desktop of older versions.
+            let id = window_x11DesktopIds(w)[0];
+            output_result({{#if name}}desktop_name(id){{else}}id{{/if}});
+            {{else}}
+            if (w.onAllDesktops) {
+                output_result("all_desktops");
+            } else {
+                let ids = window_x11DesktopIds(w);
+                for (let i = 0; i < ids.length; i++) {
+                    output_result({{#if name}}desktop_name(ids[i]){{else}}ids[i]{{/if}});
+                }
+            }
+            {{/if}}
+"#,
+    "set_desktop_for_window"=> r#"
+            let n = {{#if current}}workspace_currentDesktop(){{else}}find_desktop({{{desktop}}}){{/if}};
+            if (n != null) {
+                window_setX11DesktopId(w, n);
+                {{#if follow}}
+                workspace_setCurrentDesktop(n);
+                {{/if}}
+            }
+"#,
+    "add_desktop_for_window"=> r#"
+            let n = {{#if current}}workspace_currentDesktop(){{else}}find_desktop({{{desktop}}}){{/if}};
+            if (n != null) {
+                window_addX11DesktopId(w, n);
+            }
+"#,
+    "remove_desktop_for_window"=> r#"
+            let n = {{#if current}}workspace_currentDesktop(){{else}}find_desktop({{{desktop}}}){{/if}};
+            if (n != null) {
+                window_removeX11DesktopId(w, n);
+            }
+"#,
+    "get_activity_for_window"=> r#"
+            if (w.activities.length == 0) {
+                output_result("all_activities");
+            } else {
+                {{#if name}}
+                const names = {{{activity_names}}};
+                {{/if}}
+                for (let i = 0; i < w.activities.length; i++) {
+                    output_result({{#if name}}names[w.activities[i]] ?? w.activities[i]{{else}}w.activities[i]{{/if}});
+                }
+            }
+"#,
+    "set_activity_for_window"=> r#"
+            {{#if all_activities}}
+            w.activities = [];
+            {{else}}
+            let a = find_activity({{{activity}}});
+            if (a != null) {
+                w.activities = [a];
+            }
+            {{/if}}
+"#,
 };
 
 pub const WINDOWSTATE_PROPERTIES: phf::Map<&'static str, &'static str> = phf::phf_map! {
@@ -226,21 +771,143 @@ pub const WINDOWSTATE_PROPERTIES: phf::Map<&'static str, &'static str> = phf::ph
     "demands_attention" => "demandsAttention",
 };
 
+pub const DIRECTIONS: &[&str] = &["left", "right", "up", "down"];
+
+pub const AREAS: &[&str] = &["virtual", "output", "workarea"];
+
 pub const STEP_GLOBAL_ACTION: &str = r#"
     output_debug("STEP {{{step_name}}}")
-    {{{action}}}
+    {
+        {{{action}}}
+    }
 "#;
 
 pub const GLOBAL_ACTIONS: phf::Map<&'static str, &'static str> = phf::phf_map! {
-    "get_desktop"           => "output_result(workspace_currentDesktop());",
-    "set_desktop"           => "workspace_setCurrentDesktop({{{n}}});",
+    "get_desktop"           => "output_result({{#if name}}desktop_name(workspace_currentDesktop()){{else}}workspace_currentDesktop(){{/if}});",
+    "set_desktop"           => r#"
+        {{#if direction}}
+        workspace_setCurrentDesktop(desktop_in_direction("{{{direction}}}", {{wrap}}));
+        {{else if relative}}
+        workspace_setCurrentDesktop(desktop_relative({{{n}}}, {{wrap}}));
+        {{else}}
+        let n = find_desktop({{{desktop}}});
+        if (n != null) {
+            workspace_setCurrentDesktop(n);
+        }
+        {{/if}}
+    "#,
+    "showdesktop"           => "workspace.showingDesktop = {{#if toggle}}!workspace.showingDesktop{{else}}{{on}}{{/if}};",
     "get_num_desktops"      => "output_result(workspace_numDesktops());",
     "set_num_desktops"      => "workspace_setNumDesktops({{{n}}})",
+    "desktop_create"        => "workspace_createDesktop({{{position}}}, {{{name}}});",
+    "desktop_remove"        => r#"
+        let n = find_desktop({{{desktop}}});
+        if (n != null) {
+            workspace_removeDesktop(n);
+        }
+    "#,
+    "desktop_rename"        => r#"
+        let n = find_desktop({{{desktop}}});
+        if (n != null) {
+            workspace_renameDesktop(n, {{{name}}});
+        }
+    "#,
+    "list_desktops"         => r#"
+        let desktops = workspace_desktopList();
+        let windows = workspace_windowList();
+        for (var i = 0; i < desktops.length; i++) {
+            let d = desktops[i];
+            d.windows = windows.filter((w) => w.normalWindow && (w.onAllDesktops || window_x11DesktopIds(w).indexOf(d.number) >= 0)).length;
+            {{#unless json}}
+            output_result(`number:${d.number} id:${d.id} windows:${d.windows} name:${d.name}`);
+            {{/unless}}
+        }
+        {{#if json}}
+        output_result(JSON.stringify(desktops));
+        {{/if}}
+    "#,
+    "get_activity"          => "output_result({{#if name}}native_result.name{{else}}native_result.id{{/if}});",
+    "set_activity"          => "",
+    "list_activities"       => r#"
+        let activities = native_result;
+        let windows = workspace_windowList();
+        for (var i = 0; i < activities.length; i++) {
+            let a = activities[i];
+            a.windows = windows.filter((w) => w.normalWindow && window_onActivity(w, a.id)).length;
+            {{#unless json}}
+            output_result(`id:${a.id} current:${a.current} state:${a.state} windows:${a.windows} name:${a.name}`);
+            {{/unless}}
+        }
+        {{#if json}}
+        output_result(JSON.stringify(activities));
+        {{/if}}
+    "#,
+    "activity_create"       => "output_result(native_result);",
+    "activity_stop"         => "",
+    "getdisplaygeometry"    => r#"
+        {{#if output}}
+        let o = find_output({{{output}}}, workspace_activeOutput());
+        let g = o == null ? null : output_area(o, "output");
+        {{else}}
+        let g = workspace.virtualScreenSize;
+        {{/if}}
+        if (g != null) {
+            {{#if shell}}
+            output_result("WIDTH="+g.width);
+            output_result("HEIGHT="+g.height);
+            {{else}}
+            output_result(`${g.width} ${g.height}`);
+            {{/if}}
+        }
+    "#,
+    "getstackingorder"      => r#"
+        let windows = workspace_stackingOrder();
+        {{#if json}}
+        output_result(JSON.stringify(windows.map((w) => ({
+            id: w.internalId.toString(),
+            class: w.resourceClass,
+            name: w.caption,
+            minimized: w.minimized,
+            keep_above: w.keepAbove,
+            keep_below: w.keepBelow,
+        }))));
+        {{else}}
+        for (var i = 0; i < windows.length; i++) {
+            output_result(windows[i].internalId);
+        }
+        {{/if}}
+    "#,
+    "listoutputs"           => r#"
+        let outputs = workspace_outputs();
+        let active = workspace_activeOutput();
+        let cursor = workspace_outputAt(workspace.cursorPos);
+        let result = [];
+        for (var i = 0; i < outputs.length; i++) {
+            let o = outputs[i];
+            {{#if json}}
+            result.push({
+                index: i,
+                name: output_name(o),
+                geometry: rect_json(output_area(o, "output")),
+                workarea: rect_json(output_area(o, "workarea")),
+                scale: output_scale(o),
+                active: o == active,
+                cursor: o == cursor,
+            });
+            {{else}}
+            output_result(`index:${i} name:${output_name(o)} geometry:${rect_string(output_area(o, "output"))} workarea:${rect_string(output_area(o, "workarea"))} scale:${output_scale(o)} active:${o == active} cursor:${o == cursor}`);
+            {{/if}}
+        }
+        {{#if json}}
+        output_result(JSON.stringify(result));
+        {{/if}}
+    "#,
     "getmouselocation"      => r#"
-        let p = workspace.cursorPos;
-        let screen = workspace.screenAt(p);
+        let screen = workspace.screenAt(workspace.cursorPos);
+        let p = to_user_point(workspace.cursorPos, screen);
         let screen_id = workspace.screens.indexOf(screen);
-        let window_list = workspace.windowAt(p);
+        // windowAt() takes KWin's logical coordinates.
+        let window_list = workspace.windowAt(workspace.cursorPos);
         let window_id = "";
         window_stack = [];
         if (window_list.length > 0) {
@@ -0,0 +1,91 @@
+// A private D-Bus daemon for tests, with helpers to run stand-in services on
+// it, so that tests don't depend on a KDE session.
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use dbus::{
+    blocking::Connection,
+    channel::{Channel, MatchingReceiver, Sender},
+    message::MatchRule,
+    Message,
+};
+
+pub struct TestBus {
+    daemon: Child,
+    pub address: String,
+    done: Arc<AtomicBool>,
+}
+
+impl TestBus {
+    // Returns None if dbus-daemon isn't installed, so that tests can be
+    // skipped.
+    pub fn start() -> Option<TestBus> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|err| eprintln!("Skipping test, can't run dbus-daemon: {err}"))
+            .ok()?;
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Some(TestBus {
+            daemon,
+            address: address.trim().into(),
+            done: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn connect(&self) -> Connection {
+        let mut channel = Channel::open_private(&self.address).unwrap();
+        channel.register().unwrap();
+        channel.into()
+    }
+
+    // Run a service with the given name, which answers method calls with the
+    // reply returned by the handler, or not at all for None.
+    pub fn serve(
+        &self,
+        name: &str,
+        mut handler: impl FnMut(&Message) -> Option<Message> + Send + 'static,
+    ) {
+        let conn = self.connect();
+        conn.request_name(name, false, false, true).unwrap();
+        conn.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, conn| {
+                if let Some(reply) = handler(&message) {
+                    _ = conn.send(reply);
+                }
+                true
+            }),
+        );
+        self.spawn(conn);
+    }
+
+    // Process the messages of a connection until the bus is stopped.
+    pub fn spawn(&self, conn: Connection) {
+        let done = self.done.clone();
+        std::thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                if conn.process(Duration::from_millis(100)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        _ = self.daemon.kill();
+        _ = self.daemon.wait();
+    }
+}
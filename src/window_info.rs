@@ -0,0 +1,169 @@
+// Chains that only query windows given by ID can be answered with KWin's
+// getWindowInfo() DBus method, which is much faster than loading and running a
+// script. KDE 6 only.
+
+use std::ffi::OsString;
+use std::time::Duration;
+
+use dbus::{arg::PropMap, blocking::Connection};
+
+const QUERIES: &[&str] = &[
+    "getwindowname",
+    "getwindowclassname",
+    "getwindowgeometry",
+    "getwindowid",
+];
+
+// KWin's window IDs are UUIDs in braces, e.g.
+// {8a6b8b3f-7bd5-4c1e-9d46-3c5a1a2b9e10}.
+fn is_window_uuid(id: &str) -> bool {
+    let Some(uuid) = id.strip_prefix('{').and_then(|s| s.strip_suffix('}')) else {
+        return false;
+    };
+    let groups: Vec<&str> = uuid.split('-').collect();
+    groups.iter().map(|g| g.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|g| g.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// Returns (command, window id) pairs if every step of the chain is a query
+// without options on an explicit window ID. IDs are lower-cased, as KWin's are.
+pub fn parse_window_queries<'a>(
+    first: &'a str,
+    rest: &'a [OsString],
+) -> Option<Vec<(&'a str, String)>> {
+    let args: Vec<&str> = std::iter::once(Some(first))
+        .chain(rest.iter().map(|s| s.to_str()))
+        .collect::<Option<_>>()?;
+    let steps = args.chunks_exact(2);
+    if !steps.remainder().is_empty() {
+        return None;
+    }
+    steps
+        .map(|step| {
+            (QUERIES.contains(&step[0]) && is_window_uuid(step[1]))
+                .then(|| (step[0], step[1].to_lowercase()))
+        })
+        .collect()
+}
+
+// Returns the output lines, in the same format as the script.
+pub fn query_windows(conn: &Connection, queries: &[(&str, String)]) -> anyhow::Result<Vec<String>> {
+    let proxy = conn.with_proxy("org.kde.KWin", "/KWin", Duration::from_millis(5000));
+    let mut output = vec![];
+    for (command, id) in queries {
+        let (info,): (PropMap,) = proxy.method_call("org.kde.KWin", "getWindowInfo", (id,))?;
+        // The script ignores windows that don't exist, too.
+        if info.is_empty() {
+            continue;
+        }
+        let string = |key: &str| info.get(key).and_then(|v| v.0.as_str()).unwrap_or_default();
+        let number = |key: &str| {
+            info.get(key)
+                .and_then(|v| v.0.as_f64().or_else(|| v.0.as_i64().map(|n| n as f64)))
+                .unwrap_or_default()
+        };
+        match *command {
+            "getwindowname" => output.push(string("caption").to_string()),
+            "getwindowclassname" => output.push(string("resourceClass").to_string()),
+            "getwindowid" => output.push(string("uuid").to_string()),
+            _ => {
+                output.push(format!("Window {}", string("uuid")));
+                output.push(format!("  Position: {},{}", number("x"), number("y")));
+                output.push(format!(
+                    "  Geometry: {}x{}",
+                    number("width"),
+                    number("height")
+                ));
+            }
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use dbus::{arg::Variant, Message};
+
+    use super::*;
+    use crate::test_bus::TestBus;
+
+    const ID: &str = "{8a6b8b3f-7bd5-4c1e-9d46-3c5a1a2b9e10}";
+
+    #[test]
+    fn window_uuids() {
+        assert!(is_window_uuid(ID));
+        assert!(is_window_uuid(&ID.to_uppercase()));
+        assert!(!is_window_uuid("{}"));
+        assert!(!is_window_uuid("{not-a-uuid}"));
+        assert!(!is_window_uuid(&ID[1..]));
+        assert!(!is_window_uuid("{8a6b8b3f-7bd5-4c1e-9d46-3c5a1a2b9e1x}"));
+        assert!(!is_window_uuid("{8a6b8b3f7bd54c1e9d463c5a1a2b9e10}"));
+    }
+
+    #[test]
+    fn window_queries() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            parse_window_queries("getwindowname", &args(&[ID, "getwindowid", ID])),
+            Some(vec![
+                ("getwindowname", ID.into()),
+                ("getwindowid", ID.into())
+            ])
+        );
+        assert_eq!(
+            parse_window_queries("getwindowid", &args(&[&ID.to_uppercase()])),
+            Some(vec![("getwindowid", ID.into())])
+        );
+        assert_eq!(parse_window_queries("getwindowname", &args(&["%1"])), None);
+        assert_eq!(parse_window_queries("getwindowname", &args(&["{x}"])), None);
+        assert_eq!(parse_window_queries("getwindowname", &args(&[])), None);
+        assert_eq!(parse_window_queries("windowmove", &args(&[ID])), None);
+    }
+
+    #[test]
+    fn queries() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        // A stand-in for KWin, which knows one window.
+        bus.serve("org.kde.KWin", |message: &Message| {
+            assert_eq!(message.member().as_deref(), Some("getWindowInfo"));
+            let mut info = PropMap::new();
+            if message.get1::<String>().as_deref() == Some(ID) {
+                info.insert("uuid".into(), Variant(Box::new(ID.to_string())));
+                info.insert("caption".into(), Variant(Box::new("Konsole".to_string())));
+                info.insert(
+                    "resourceClass".into(),
+                    Variant(Box::new("konsole".to_string())),
+                );
+                info.insert("x".into(), Variant(Box::new(10.0)));
+                info.insert("y".into(), Variant(Box::new(20.5)));
+                info.insert("width".into(), Variant(Box::new(800i32)));
+                info.insert("height".into(), Variant(Box::new(600.0)));
+            }
+            Some(message.method_return().append1(info))
+        });
+        let conn = bus.connect();
+        let other = "{00000000-0000-0000-0000-000000000000}";
+        let queries = [
+            ("getwindowname", ID.to_string()),
+            ("getwindowclassname", ID.into()),
+            ("getwindowid", ID.into()),
+            ("getwindowname", other.into()),
+            ("getwindowgeometry", ID.into()),
+        ];
+        assert_eq!(
+            query_windows(&conn, &queries).unwrap(),
+            [
+                "Konsole".to_string(),
+                "konsole".into(),
+                ID.into(),
+                format!("Window {ID}"),
+                "  Position: 10,20.5".into(),
+                "  Geometry: 800x600".into(),
+            ]
+        );
+    }
+}